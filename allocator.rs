@@ -1,12 +1,117 @@
-use std::cmp::Ordering;
-use std::rc::{Rc, Weak};
+// Everything but the host tools below builds on core and alloc alone, so boot.rs can
+// include the same modules in the kernel, where there is no std
+extern crate alloc;
 
+use alloc::boxed::Box;
+use alloc::rc::{Rc, Weak};
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::cell::{Cell, RefCell};
+use core::cmp::{Ordering, Reverse};
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Bound, RangeBounds};
+
+mod debug;
+mod locked;
+mod magazine;
+#[cfg(not(target_os = "none"))]
+mod memory_map;
+mod slab;
+mod snapshot;
+mod tlsf;
+mod trace;
+mod zone;
+
+use trace::TraceRecorder;
+
+const PAGE_SIZE: usize = 4096; // 4096 bytes = 4 kilobytes
+
+const USER_MEM_START: usize = 0x10000000;
+const USER_MEM_SIZE: usize = 0x10000000;
+
+const MAX_BLOCK_SIZE_EXP: u32 = 20;
+const MIN_BLOCK_SIZE_EXP: u32 = 12;
+
+#[derive(Debug, PartialEq, Eq)]
+enum AllocError {
+    // The block at this address was already handed back
+    DoubleFree(usize),
+    // No block was ever allocated at this address
+    UnknownAddress(usize),
+    // The AllocatorConfig cannot describe a usable region
+    InvalidConfig(&'static str),
+    // Zero sized, or aligned beyond the largest block
+    InvalidLayout(Layout),
+    // Too many bytes for any Layout to describe
+    TooLarge(usize),
+    // No free block can hold the layout
+    OutOfMemory(Layout),
+    // check_free_lists found a bad link in the chain for size byte blocks
+    CorruptFreeList { size: usize, address: usize },
+    // A reference was dropped from the block at this address when it had none left
+    RefCountUnderflow(usize),
+    // Handing out requested more bytes would take tag past its quota
+    QuotaExceeded { tag: OwnerTag, used: usize, limit: usize, requested: usize },
+    // A LockedAllocator was used before init gave it a region
+    Uninitialized,
+    // Part of the range is allocated, so it can't be reserved
+    RangeInUse { start: usize, size: usize },
+    // check_reservations found a block reaching into a reserved range
+    ReservedOverlap { block: usize, reserved: usize },
+    // The range runs past the end of the address space
+    RangeOverflow { start: usize, len: usize },
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::DoubleFree(start) => write!(f, "double free of block at 0x{:x}", start),
+            AllocError::UnknownAddress(start) => write!(f, "no allocated block at 0x{:x}", start),
+            AllocError::InvalidConfig(reason) => write!(f, "invalid allocator config: {}", reason),
+            AllocError::InvalidLayout(layout) => write!(f, "cannot allocate {:?}", layout),
+            AllocError::TooLarge(size) => write!(f, "cannot allocate {} bytes", size),
+            AllocError::OutOfMemory(layout) => write!(f, "out of memory allocating {:?}", layout),
+            AllocError::CorruptFreeList { size, address } => {
+                write!(f, "free list of {} byte blocks is corrupt at 0x{:x}", size, address)
+            }
+            AllocError::RefCountUnderflow(start) => {
+                write!(f, "reference count of block at 0x{:x} dropped below zero", start)
+            }
+            AllocError::QuotaExceeded { tag, used, limit, requested } => write!(
+                f,
+                "{} holds {} of its {} byte quota and asked for {} more",
+                tag, used, limit, requested
+            ),
+            AllocError::Uninitialized => write!(f, "allocator used before it was initialized"),
+            AllocError::RangeInUse { start, size } => {
+                write!(f, "0x{:x}-0x{:x} is partly allocated", start, start + size - 1)
+            }
+            AllocError::ReservedOverlap { block, reserved } => {
+                write!(f, "block at 0x{:x} overlaps the range reserved at 0x{:x}", block, reserved)
+            }
+            AllocError::RangeOverflow { start, len } => {
+                write!(f, "{} bytes at 0x{:x} run past the end of the address space", len, start)
+            }
+        }
+    }
+}
+
+// While a block is free it is shared through an Rc, so its free list links are
+// mutated in place through the cells
+#[derive(Debug)]
 struct MemoryBlock {
     pages: Vec<usize>,
     free: bool,
-    next_block: Option<Weak<MemoryBlock>>,
-    next_block_size: usize,
+    next_block: RefCell<Option<Weak<MemoryBlock>>>,
+    next_block_size: Cell<usize>,
+    // The block before this one on its free chain, so it can be unlinked without a walk
+    prev_block: RefCell<Option<Weak<MemoryBlock>>>,
     refs: usize,
+    // Who the block was allocated for, while it is in use
+    owner: Option<OwnerTag>,
+    // Alignment the block was allocated with, which it keeps if compact moves it
+    align: usize,
 }
 
 impl MemoryBlock {
@@ -14,71 +119,221 @@ impl MemoryBlock {
         MemoryBlock {
             pages,
             free,
-            next_block,
-            next_block_size,
+            next_block: RefCell::new(next_block),
+            next_block_size: Cell::new(next_block_size),
+            prev_block: RefCell::new(None),
             refs: 0,
+            owner: None,
+            align: 1,
         }
     }
 
+    // Builds a block covering [start, start + size) one page at a time
+    fn from_range(start: usize, size: usize, free: bool) -> MemoryBlock {
+        let pages = (start..start + size).step_by(PAGE_SIZE).collect();
+        MemoryBlock::new(pages, free, None, 0)
+    }
+
     fn size(&self) -> usize {
-        self.pages.len() * 4096 // 4096 bytes = 4 kilobytes
+        self.pages.len() * PAGE_SIZE
+    }
+
+    fn start(&self) -> usize {
+        self.pages[0]
+    }
+
+    // The buddy of a block is the other half of the block it was split from
+    fn buddy_address(&self) -> usize {
+        self.start() ^ self.size()
     }
 
-    fn add_ref(&mut self) {
+    // Shortens the block to size bytes, returning the pages cut from its end
+    fn split_off(&mut self, size: usize) -> MemoryBlock {
+        let tail_pages = self.pages.split_off(size / PAGE_SIZE);
+        MemoryBlock::new(tail_pages, self.free, None, 0)
+    }
+
+    // Joins two buddies back into the block they were split from
+    fn merge(self, other: MemoryBlock) -> MemoryBlock {
+        let (mut lower, mut upper) = if self.start() < other.start() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        lower.pages.append(&mut upper.pages);
+        lower
+    }
+
+    fn add_ref(&mut self) -> usize {
         self.refs += 1;
+        self.refs
     }
 
-    fn remove_ref(&mut self) {
+    // Returns how many references are left
+    fn remove_ref(&mut self) -> Result<usize, AllocError> {
+        if self.refs == 0 {
+            return Err(AllocError::RefCountUnderflow(self.start()));
+        }
         self.refs -= 1;
+        Ok(self.refs)
+    }
+}
+
+// The free blocks of one size, chained newest first through MemoryBlock::next_block and
+// back through prev_block. The links are weak: free_addresses owns the blocks and the
+// chain only orders them.
+#[derive(Debug, Default)]
+struct FreeChain {
+    head: Option<Weak<MemoryBlock>>,
+    len: usize,
+}
+
+impl FreeChain {
+    fn push(&mut self, block: &Rc<MemoryBlock>) {
+        let next = self.head.take();
+        if let Some(head) = next.as_ref().and_then(Weak::upgrade) {
+            *head.prev_block.borrow_mut() = Some(Rc::downgrade(block));
+        }
+        block.next_block_size.set(if next.is_some() { block.size() } else { 0 });
+        *block.next_block.borrow_mut() = next;
+        *block.prev_block.borrow_mut() = None;
+        self.head = Some(Rc::downgrade(block));
+        self.len += 1;
+    }
+
+    fn first(&self) -> Option<Rc<MemoryBlock>> {
+        self.head.as_ref()?.upgrade()
+    }
+
+    // Unlinks block, which has to be on this chain, by joining its neighbours
+    fn unlink(&mut self, block: &MemoryBlock) {
+        let next = block.next_block.borrow_mut().take();
+        let prev = block.prev_block.borrow_mut().take();
+
+        if let Some(next) = next.as_ref().and_then(Weak::upgrade) {
+            *next.prev_block.borrow_mut() = prev.clone();
+        }
+        match prev.as_ref().and_then(Weak::upgrade) {
+            Some(prev) => {
+                prev.next_block_size.set(block.next_block_size.get());
+                *prev.next_block.borrow_mut() = next;
+            }
+            None => self.head = next,
+        }
+        block.next_block_size.set(0);
+        self.len -= 1;
+    }
+}
+
+// Measures a value so every node can also track the largest measure in its subtree.
+// Trees that don't need it use NoAugment, where every measure is zero.
+trait Augment<V> {
+    fn measure(value: &V) -> usize;
+}
+
+#[derive(Debug)]
+struct NoAugment;
+
+impl<V> Augment<V> for NoAugment {
+    fn measure(_: &V) -> usize {
+        0
+    }
+}
+
+// For free blocks keyed by start address, measured by their size
+#[derive(Debug)]
+struct LargestFree;
+
+impl Augment<Rc<MemoryBlock>> for LargestFree {
+    fn measure(block: &Rc<MemoryBlock>) -> usize {
+        block.size()
     }
 }
 
 #[derive(Debug)]
-struct AVLTree<K: Ord, V> {
-    root: Option<Box<Node<K, V>>>,
+struct AVLTree<K: Ord, V, A: Augment<V> = NoAugment> {
+    root: Link<K, V>,
+    augment: PhantomData<A>,
 }
 
+// Tree nodes looked at and rotations made on this thread, the unit of work the TLSF
+// test weighs against TLSF's list operations. Only test builds count them.
+#[cfg(test)]
+std::thread_local! {
+    static TREE_VISITS: Cell<usize> = const { Cell::new(0) };
+}
+
+#[cfg(test)]
+fn tree_visits() -> usize {
+    TREE_VISITS.with(Cell::get)
+}
+
+// A subtree, empty or rooted at a node
+type Link<K, V> = Option<Box<Node<K, V>>>;
+
 #[derive(Debug)]
 struct Node<K: Ord, V> {
     key: K,
     value: V,
-    memory:  MemoryBlock,
     height: i32,
-    left: Option<Box<Node<K, V>>>,
-    right: Option<Box<Node<K, V>>>,
+    // Largest measure of any value in this subtree
+    subtree_max: usize,
+    left: Link<K, V>,
+    right: Link<K, V>,
 }
 
-impl<K: Ord, V> AVLTree<K, V> {
+impl<K: Ord, V, A: Augment<V>> AVLTree<K, V, A> {
     fn new() -> Self {
-        println!("anyone");
-        AVLTree { root: None };
-        println!("new tree made");
+        AVLTree {
+            root: None,
+            augment: PhantomData,
+        }
+    }
+
+    fn visit() {
+        #[cfg(test)]
+        TREE_VISITS.with(|visits| visits.set(visits.get() + 1));
     }
 
-    fn height(node: &Option<Box<Node<K, V>>>) -> i32 {
+    fn height(node: &Link<K, V>) -> i32 {
         node.as_ref().map_or(-1, |n| n.height)
     }
 
+    // Levels from the root down to the deepest leaf
+    fn depth(&self) -> usize {
+        (Self::height(&self.root) + 1) as usize
+    }
+
+    fn subtree_max(node: &Link<K, V>) -> usize {
+        node.as_ref().map_or(0, |n| n.subtree_max)
+    }
+
+    // Recomputes the height and subtree_max of a node from its children
+    fn update(node: &mut Box<Node<K, V>>) {
+        node.height = 1 + core::cmp::max(Self::height(&node.left), Self::height(&node.right));
+        node.subtree_max = A::measure(&node.value)
+            .max(Self::subtree_max(&node.left))
+            .max(Self::subtree_max(&node.right));
+    }
+
     fn rotate_right(mut node: Box<Node<K, V>>) -> Box<Node<K, V>> {
+        Self::visit();
         let mut new_root = node.left.take().unwrap();
         node.left = new_root.right.take();
+        Self::update(&mut node);
         new_root.right = Some(node);
-
-        node.height = 1 + std::cmp::max(Self::height(&node.left), Self::height(&node.right));
-        new_root.height =
-            1 + std::cmp::max(Self::height(&new_root.left), Self::height(&new_root.right));
+        Self::update(&mut new_root);
 
         new_root
     }
 
     fn rotate_left(mut node: Box<Node<K, V>>) -> Box<Node<K, V>> {
+        Self::visit();
         let mut new_root = node.right.take().unwrap();
         node.right = new_root.left.take();
+        Self::update(&mut node);
         new_root.left = Some(node);
-
-        node.height = 1 + std::cmp::max(Self::height(&node.left), Self::height(&node.right));
-        new_root.height =
-            1 + std::cmp::max(Self::height(&new_root.left), Self::height(&new_root.right));
+        Self::update(&mut new_root);
 
         new_root
     }
@@ -91,6 +346,8 @@ impl<K: Ord, V> AVLTree<K, V> {
             let left = node.left.take().unwrap();
             if Self::height(&left.left) < Self::height(&left.right) {
                 node.left = Some(Self::rotate_left(left));
+            } else {
+                node.left = Some(left);
             }
             return Self::rotate_right(node);
         }
@@ -99,69 +356,109 @@ impl<K: Ord, V> AVLTree<K, V> {
             let right = node.right.take().unwrap();
             if Self::height(&right.right) < Self::height(&right.left) {
                 node.right = Some(Self::rotate_right(right));
+            } else {
+                node.right = Some(right);
             }
             return Self::rotate_left(node);
         }
 
-        node.height = 1 + std::cmp::max(Self::height(&node.left), Self::height(&node.right));
+        Self::update(&mut node);
         node
     }
 
-    fn insert_node(
-        node: &mut Option<Box<Node<K, V>>>,
-        key: K,
-        value: V,
-    ) -> Option<Box<Node<K, V>>> {
-        if let Some(node) = node {
+    // Returns the rebalanced subtree
+    fn insert_node(node: Link<K, V>, key: K, value: V) -> Box<Node<K, V>> {
+        if let Some(mut node) = node {
+            Self::visit();
             match key.cmp(&node.key) {
-                Ordering::Less => node.left = Self::insert_node(&mut node.left, key, value),
-                Ordering::Greater => node.right = Self::insert_node(&mut node.right, key, value),
+                Ordering::Less => node.left = Some(Self::insert_node(node.left.take(), key, value)),
+                Ordering::Greater => node.right = Some(Self::insert_node(node.right.take(), key, value)),
                 Ordering::Equal => {
                     node.value = value;
-                    return Some(node.clone());
+                    Self::update(&mut node);
+                    return node;
                 }
             }
-            Some(Self::balance(node.clone()))
+            Self::balance(node)
         } else {
-            Some(Box::new(Node {
-                key,
-                value,
-                height: 0,
-                left: None,
-                right: None,
-            }));
+            Self::leaf(key, value)
+        }
+    }
+
+    fn leaf(key: K, value: V) -> Box<Node<K, V>> {
+        Box::new(Node {
+            subtree_max: A::measure(&value),
+            key,
+            value,
+            height: 0,
+            left: None,
+            right: None,
+        })
+    }
+
+    // Hangs subtree back under the nodes entry walked past, deepest first, rebalancing
+    // each on the way up if the subtree was just inserted
+    fn zip(path: Vec<(Box<Node<K, V>>, Ordering)>, subtree: Box<Node<K, V>>, rebalance: bool) -> Box<Node<K, V>> {
+        path.into_iter().rev().fold(subtree, |child, (mut parent, side)| {
+            match side {
+                Ordering::Less => parent.left = Some(child),
+                _ => parent.right = Some(child),
+            }
+            if rebalance {
+                Self::balance(parent)
+            } else {
+                parent
+            }
+        })
+    }
+
+    // Detaches the smallest node of a subtree, returning the rebalanced rest and the node
+    fn remove_min(mut node: Box<Node<K, V>>) -> (Link<K, V>, Box<Node<K, V>>) {
+        Self::visit();
+        match node.left.take() {
+            None => (node.right.take(), node),
+            Some(left) => {
+                let (rest, min) = Self::remove_min(left);
+                node.left = rest;
+                (Some(Self::balance(node)), min)
+            }
         }
     }
 
-    fn remove_node(node: &mut Option<Box<Node<K, V>>>, key: &K) -> Option<Box<Node<K, V>>> {
-        if let Some(ref mut node) = node {
+    fn remove_node(node: Link<K, V>, key: &K) -> (Link<K, V>, Option<V>) {
+        if let Some(mut node) = node {
+            Self::visit();
+            let removed;
             match key.cmp(&node.key) {
-                Ordering::Less => node.left = Self::remove_node(&mut node.left, key),
-                Ordering::Greater => node.right = Self::remove_node(&mut node.right, key),
+                Ordering::Less => {
+                    let (left, value) = Self::remove_node(node.left.take(), key);
+                    node.left = left;
+                    removed = value;
+                }
+                Ordering::Greater => {
+                    let (right, value) = Self::remove_node(node.right.take(), key);
+                    node.right = right;
+                    removed = value;
+                }
                 Ordering::Equal => {
-                    if node.left.is_none() {
-                        return node.right.take();
-                    } else if node.right.is_none() {
-                        return node.left.take();
-                    } else {
-                        let mut right = node.right.take().unwrap();
-                        let mut min = right.as_mut();
-
-                        while min.left.is_some() {
-                            min = min.left.as_mut().unwrap();
+                    let Node { value, left, right, .. } = *node;
+                    let replacement = match (left, right) {
+                        (None, right) => right,
+                        (left, None) => left,
+                        (left, Some(right)) => {
+                            let (rest, mut min) = Self::remove_min(right);
+                            min.left = left;
+                            min.right = rest;
+                            Some(Self::balance(min))
                         }
-
-                        std::mem::swap(&mut node.key, &mut min.key);
-                        std::mem::swap(&mut node.value, &mut min.value);
-
-                        node.right = Self::remove_node(&mut node.right, &min.key);
-                    }
+                    };
+                    return (replacement, Some(value));
                 }
             }
 
-            Some(Self::balance(node.clone()))
+            (Some(Self::balance(node)), removed)
         } else {
-            None
+            (None, None)
         }
     }
 
@@ -169,6 +466,7 @@ impl<K: Ord, V> AVLTree<K, V> {
         let mut current = &self.root;
 
         while let Some(node) = current {
+            Self::visit();
             match key.cmp(&node.key) {
                 Ordering::Less => current = &node.left,
                 Ordering::Greater => current = &node.right,
@@ -179,77 +477,154 @@ impl<K: Ord, V> AVLTree<K, V> {
         None
     }
 
-    fn remove(&mut self, key: K) -> Option<V> {
-        let removed = Self::remove_node(&mut self.root, &key);
-        removed.map(|node| node.value)
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (root, removed) = Self::remove_node(self.root.take(), key);
+        self.root = root;
+        removed
     }
 
     fn insert(&mut self, key: K, value: V) {
-        self.root = Self::insert_node(&mut self.root, key, value);
+        self.root = Some(Self::insert_node(self.root.take(), key, value));
     }
-}
 
-#[derive(Debug)]
-struct Allocator {
-    memory_tree: AVLTree<usize, usize>,
-}
+    // Entries in ascending key order
+    fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.root, false)
+    }
 
-impl Allocator {
-    pub fn new() -> Self {
-        const USER_MEM_START: usize = 0x10000000;
-        const USER_MEM_SIZE: usize = 0x10000000;
+    // Entries in descending key order. Like first and iter_mut_rev, nothing in the
+    // allocator needs it yet, but it is part of the ordered map the tree offers.
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn iter_rev(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.root, true)
+    }
+
+    // Entries whose keys fall inside range, in ascending key order
+    fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V>
+    where
+        K: Clone,
+    {
+        let mut stack = Vec::new();
+        let mut current = &self.root;
+
+        // Keep the path of nodes at or above the lower bound, skipping everything below it
+        while let Some(node) = current {
+            Self::visit();
+            let above_start = match range.start_bound() {
+                Bound::Included(start) => node.key >= *start,
+                Bound::Excluded(start) => node.key > *start,
+                Bound::Unbounded => true,
+            };
+            if above_start {
+                stack.push(node.as_ref());
+                current = &node.left;
+            } else {
+                current = &node.right;
+            }
+        }
 
-        const MAX_BLOCK_SIZE_EXP: u32 = 20;
-        const MIN_BLOCK_SIZE_EXP: u32 = 12;
+        Range {
+            iter: Iter { stack, reverse: false },
+            end: range.end_bound().cloned(),
+        }
+    }
 
-        let mut memory_tree = AVLTree::<usize, usize>::new();
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn first(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_ref()?;
+        Self::visit();
+        while let Some(left) = node.left.as_ref() {
+            Self::visit();
+            node = left;
+        }
+        Some((&node.key, &node.value))
+    }
 
-        let mut remaining_memory = USER_MEM_SIZE;
-        let mut current_address = USER_MEM_START;
+    fn last(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_ref()?;
+        Self::visit();
+        while let Some(right) = node.right.as_ref() {
+            Self::visit();
+            node = right;
+        }
+        Some((&node.key, &node.value))
+    }
 
-        while remaining_memory > 0 {
-            let mut block_size_exp = MIN_BLOCK_SIZE_EXP;
-            let max_exp = MAX_BLOCK_SIZE_EXP.min((remaining_memory as f64).log2().floor() as u32);
-
-            let exp_range = MIN_BLOCK_SIZE_EXP..=max_exp;
-            let exp_weights = exp_range
-                .clone()
-                .map(|x| (MAX_BLOCK_SIZE_EXP - x) as f64)
-                .collect::<Vec<_>>();
-            let sum_weights = exp_weights.iter().sum::<f64>();
-
-            let random_weight: f64 = rand::random::<f64>() * sum_weights;
-
-            let mut cumulative_weight = 0.0;
-            for (exp, &weight) in exp_range.zip(exp_weights.iter()) {
-                cumulative_weight += weight;
-                if cumulative_weight >= random_weight {
-                    block_size_exp = exp;
-                    break;
+    // Entry with the greatest key less than or equal to key
+    fn floor(&self, key: &K) -> Option<(&K, &V)> {
+        let mut current = &self.root;
+        let mut best = None;
+
+        while let Some(node) = current {
+            Self::visit();
+            match key.cmp(&node.key) {
+                Ordering::Less => current = &node.left,
+                Ordering::Greater => {
+                    best = Some((&node.key, &node.value));
+                    current = &node.right;
                 }
+                Ordering::Equal => return Some((&node.key, &node.value)),
             }
+        }
 
-            let block_size = 1 << block_size_exp;
-            let block_end = current_address + block_size - 1;
-            memory_tree.insert(block_size, current_address, current_address, block_end);
-            current_address += block_size;
-            remaining_memory -= block_size;
+        best
+    }
+
+    // Entry with the smallest key greater than or equal to key
+    fn ceiling(&self, key: &K) -> Option<(&K, &V)> {
+        let mut current = &self.root;
+        let mut best = None;
+
+        while let Some(node) = current {
+            Self::visit();
+            match key.cmp(&node.key) {
+                Ordering::Less => {
+                    best = Some((&node.key, &node.value));
+                    current = &node.left;
+                }
+                Ordering::Greater => current = &node.right,
+                Ordering::Equal => return Some((&node.key, &node.value)),
+            }
+        }
+
+        best
+    }
+
+    // Lowest keyed entry at or after from whose value measures at least size
+    fn first_fit_from(&self, from: &K, size: usize) -> Option<(&K, &V)> {
+        fn descend<'a, K: Ord, V, A: Augment<V>>(
+            node: &'a Link<K, V>,
+            from: &K,
+            size: usize,
+        ) -> Option<&'a Node<K, V>> {
+            let node = node.as_ref().filter(|node| node.subtree_max >= size)?;
+            AVLTree::<K, V, A>::visit();
+            if node.key < *from {
+                return descend::<K, V, A>(&node.right, from, size);
+            }
+            descend::<K, V, A>(&node.left, from, size)
+                .or_else(|| Some(node.as_ref()).filter(|node| A::measure(&node.value) >= size))
+                .or_else(|| descend::<K, V, A>(&node.right, from, size))
         }
 
-        Allocator { memory_tree }
+        descend::<K, V, A>(&self.root, from, size).map(|node| (&node.key, &node.value))
     }
 
-    pub fn allocate_block(&self, size: usize) -> Option<(usize, usize)> {
-        let mut current = &self.memory_tree.root;
+    // Lowest keyed entry whose value measures at least size. Subtrees whose largest
+    // measure is too small are skipped whole, so this is a single descent.
+    fn first_fit(&self, size: usize) -> Option<(&K, &V)> {
+        let mut current = &self.root;
 
         while let Some(node) = current {
-            if node.key >= size {
-                return Some((node.start, node.end));
+            Self::visit();
+            if node.left.is_some() && Self::subtree_max(&node.left) >= size {
+                current = &node.left;
+            } else if A::measure(&node.value) >= size {
+                return Some((&node.key, &node.value));
+            } else if Self::subtree_max(&node.right) >= size {
+                current = &node.right;
             } else {
-                match size.cmp(&node.key) {
-                    Ordering::Less => current = &node.left,
-                    _ => current = &node.right,
-                }
+                return None;
             }
         }
 
@@ -257,62 +632,2298 @@ impl Allocator {
     }
 }
 
-// ... (AVLTree implementation) ...
+// Mutable access to values is only offered where nothing is derived from them; in an
+// augmented tree it would let subtree_max go stale.
+impl<K: Ord, V> AVLTree<K, V> {
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let mut current = &mut self.root;
+
+        while let Some(node) = current {
+            Self::visit();
+            match key.cmp(&node.key) {
+                Ordering::Less => current = &mut node.left,
+                Ordering::Greater => current = &mut node.right,
+                Ordering::Equal => return Some(&mut node.value),
+            }
+        }
+
+        None
+    }
+
+    // Walks down the tree once, taking each node it passes off its parent. An occupied
+    // entry hangs them straight back; a vacant one holds on to them, so filling it puts
+    // the new node at the bottom and rebalances on the way back up without a second
+    // search. Nothing is rebalanced unless a node is added.
+    fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        let mut path = Vec::with_capacity(self.depth());
+        let mut current = self.root.take();
+        while let Some(mut node) = current {
+            Self::visit();
+            let side = key.cmp(&node.key);
+            current = match side {
+                Ordering::Less => node.left.take(),
+                Ordering::Greater => node.right.take(),
+                Ordering::Equal => {
+                    // Nodes are boxed, so hanging this one back moves the box and not
+                    // the value, and the tree owns it for as long as it is borrowed
+                    let value: *mut V = &mut node.value;
+                    self.root = Some(Self::zip(path, node, false));
+                    return Entry::Occupied(OccupiedEntry { value: unsafe { &mut *value } });
+                }
+            };
+            path.push((node, side));
+        }
+        Entry::Vacant(VacantEntry { tree: self, key: Some(key), path })
+    }
+
+    fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut::new(&mut self.root, false)
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn iter_mut_rev(&mut self) -> IterMut<'_, K, V> {
+        IterMut::new(&mut self.root, true)
+    }
+}
+
+enum Entry<'a, K: Ord, V> {
+    Occupied(OccupiedEntry<'a, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
 
-// Update the insert method in AVLTree to accommodate the start and end boundaries
-fn insert(&mut self, key: K, value: V, start: usize, end: usize) {
-    self.root = Self::insert_node(&mut self.root, key, value, start, end);
+struct OccupiedEntry<'a, V> {
+    value: &'a mut V,
 }
 
-// Update the insert_node method in AVLTree to accept start and end parameters
-fn insert_node(
-    node: &mut Option<Box<Node<K, V>>>,
-    key: K,
-    value: V,
-    start: usize,
-    end: usize,
-) -> Option<Box<Node<K, V>>> {
-    if let Some(node) = node {
-        match key.cmp(&node.key) {
-            Ordering::Less => node.left = Self::insert_node(&mut node.left, key, value, start, end),
-            Ordering::Greater => {
-                node.right = Self::insert_node(&mut node.right, key, value, start, end)
-            }
-            Ordering::Equal => {
-                node.value = value;
-                node.start = start;
-                node.end = end;
-                return Some(node.clone());
-            }
-        }
-        Some(Self::balance(node.clone()))
-    } else {
-        Some(Box::new(Node {
-            key,
-            value,
-            start,
-            end,
-            height: 0,
-            left: None,
-            right: None,
-        }))
+// Until it is filled or dropped, the tree is missing the nodes on the way down to
+// where key belongs
+struct VacantEntry<'a, K: Ord, V> {
+    tree: &'a mut AVLTree<K, V>,
+    key: Option<K>,
+    // Every node walked past, with the side key went down, which has been taken off it
+    path: Vec<(Box<Node<K, V>>, Ordering)>,
+}
+
+impl<'a, K: Ord, V> Entry<'a, K, V> {
+    fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.value,
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(entry) => {
+                f(entry.value);
+                Entry::Occupied(entry)
+            }
+            vacant => vacant,
+        }
     }
 }
 
-// Example usage
-fn main() {
-    let allocator = Allocator::new();
-    let requested_size = 4096;
-    let allocated_block = allocator.allocate_block(requested_size);
-    match allocated_block {
-        Some((start, end)) => {
-            println!(
-                "Allocated block for size {}: start = 0x{:x}, end = 0x{:x}",
-                requested_size, start, end
-            );
+impl<'a, K: Ord, V> VacantEntry<'a, K, V> {
+    fn insert(mut self, value: V) -> &'a mut V {
+        let key = self.key.take().expect("vacant entry is filled once");
+        let mut node = AVLTree::<K, V>::leaf(key, value);
+        // As in entry, the value stays put while its box moves into the tree, which
+        // stays borrowed for 'a
+        let slot: *mut V = &mut node.value;
+        let path = core::mem::take(&mut self.path);
+        self.tree.root = Some(AVLTree::<K, V>::zip(path, node, true));
+        unsafe { &mut *slot }
+    }
+}
+
+impl<K: Ord, V> Drop for VacantEntry<'_, K, V> {
+    // Left unfilled, the tree gets its nodes back just as they were
+    fn drop(&mut self) {
+        let mut path = core::mem::take(&mut self.path);
+        if let Some((subtree, _)) = path.pop() {
+            self.tree.root = Some(AVLTree::<K, V>::zip(path, subtree, false));
         }
-        None => {
-            println!("No suitable block found for size {}", requested_size);
+    }
+}
+
+impl<'a, K: Ord, V, A: Augment<V>> IntoIterator for &'a AVLTree<K, V, A> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a mut AVLTree<K, V> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+// In-order walk holding the path of nodes still to be visited. A reverse walk mirrors
+// it, descending right first.
+struct Iter<'a, K: Ord, V> {
+    stack: Vec<&'a Node<K, V>>,
+    reverse: bool,
+}
+
+impl<'a, K: Ord, V> Iter<'a, K, V> {
+    fn new(root: &'a Link<K, V>, reverse: bool) -> Self {
+        let mut iter = Iter { stack: Vec::new(), reverse };
+        iter.push_spine(root);
+        iter
+    }
+
+    fn push_spine(&mut self, mut current: &'a Link<K, V>) {
+        while let Some(node) = current {
+            self.stack.push(node);
+            current = if self.reverse { &node.right } else { &node.left };
+        }
+    }
+}
+
+impl<'a, K: Ord, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.push_spine(if self.reverse { &node.left } else { &node.right });
+        Some((&node.key, &node.value))
+    }
+}
+
+// Mutable in-order walk. Each stacked entry splits its node into the key, the value and
+// the one subtree not yet walked, so no two live borrows overlap.
+struct IterMut<'a, K: Ord, V> {
+    stack: Vec<(&'a K, &'a mut V, &'a mut Link<K, V>)>,
+    reverse: bool,
+}
+
+impl<'a, K: Ord, V> IterMut<'a, K, V> {
+    fn new(root: &'a mut Link<K, V>, reverse: bool) -> Self {
+        let mut iter = IterMut { stack: Vec::new(), reverse };
+        iter.push_spine(root);
+        iter
+    }
+
+    fn push_spine(&mut self, mut current: &'a mut Link<K, V>) {
+        while let Some(node) = current {
+            let Node { key, value, left, right, .. } = node.as_mut();
+            if self.reverse {
+                self.stack.push((key, value, left));
+                current = right;
+            } else {
+                self.stack.push((key, value, right));
+                current = left;
+            }
+        }
+    }
+}
+
+impl<'a, K: Ord, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value, rest) = self.stack.pop()?;
+        self.push_spine(rest);
+        Some((key, value))
+    }
+}
+
+struct Range<'a, K: Ord, V> {
+    iter: Iter<'a, K, V>,
+    end: Bound<K>,
+}
+
+impl<'a, K: Ord, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.iter.next()?;
+        let in_range = match &self.end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+        if in_range {
+            Some((key, value))
+        } else {
+            self.iter.stack.clear();
+            None
+        }
+    }
+}
+
+// How Allocator::with_config carves the region into its initial free blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegionLayout {
+    // Randomly sized blocks, weighted towards the smaller orders
+    Random,
+    // The largest aligned block that fits at each address, with no randomness
+    LargestAligned,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AllocatorConfig {
+    region_start: usize,
+    region_size: usize,
+    min_block_exp: u32,
+    max_block_exp: u32,
+    // Seed for the Random layout; None draws a fresh seed on every run
+    seed: Option<u64>,
+    layout: RegionLayout,
+}
+
+impl Default for AllocatorConfig {
+    fn default() -> Self {
+        AllocatorConfig {
+            region_start: USER_MEM_START,
+            region_size: USER_MEM_SIZE,
+            min_block_exp: MIN_BLOCK_SIZE_EXP,
+            max_block_exp: MAX_BLOCK_SIZE_EXP,
+            seed: None,
+            layout: RegionLayout::Random,
+        }
+    }
+}
+
+impl AllocatorConfig {
+    fn region(mut self, start: usize, size: usize) -> Self {
+        self.region_start = start;
+        self.region_size = size;
+        self
+    }
+
+    fn block_exps(mut self, min: u32, max: u32) -> Self {
+        self.min_block_exp = min;
+        self.max_block_exp = max;
+        self
+    }
+
+    fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    fn layout(mut self, layout: RegionLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    fn validate(&self) -> Result<(), AllocError> {
+        if self.max_block_exp < self.min_block_exp || self.max_block_exp >= usize::BITS {
+            return Err(AllocError::InvalidConfig("block exponents out of range"));
+        }
+        let min_block_size = 1usize << self.min_block_exp;
+        if min_block_size < PAGE_SIZE {
+            return Err(AllocError::InvalidConfig("minimum block is smaller than a page"));
+        }
+        if self.region_size == 0 || self.region_start.checked_add(self.region_size).is_none() {
+            return Err(AllocError::InvalidConfig("region is empty or overflows"));
+        }
+        if !self.region_start.is_multiple_of(min_block_size) || !self.region_size.is_multiple_of(min_block_size) {
+            return Err(AllocError::InvalidConfig("region is not aligned to the minimum block"));
+        }
+        Ok(())
+    }
+}
+
+// A seed that differs from run to run
+#[cfg(not(target_os = "none"))]
+fn fresh_seed() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    RandomState::new().build_hasher().finish()
+}
+
+// The kernel has no entropy source yet; the cycle counter at least differs per boot
+#[cfg(target_os = "none")]
+fn fresh_seed() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// xorshift64, enough to make the Random layout reproducible from a seed
+#[derive(Debug)]
+struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    fn new(seed: u64) -> Self {
+        // An all zero state would only ever produce zeros
+        XorShift64 { state: seed.max(1) }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// One step of a Workload, against the caller's list of live blocks
+#[cfg(not(target_os = "none"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    // Free the live block at this index
+    Free(usize),
+    // Resize the live block at this index to this many bytes
+    Resize(usize, usize),
+    Allocate(Layout),
+}
+
+// A reproducible random mix of allocations, frees and resizes, for the tests, the
+// benchmarks and the trace recorder. The caller keeps the live blocks; each step only
+// says what to do next, given how many there are.
+#[cfg(not(target_os = "none"))]
+#[derive(Debug)]
+struct Workload {
+    rng: XorShift64,
+    // Out of every 12 steps with something live, how many free a block and how many
+    // resize one; the rest allocate
+    frees: u64,
+    resizes: u64,
+    // Sizes are drawn from 1 to max_size, alignments from 1 to 2^max_align_exp
+    max_size: usize,
+    max_align_exp: u32,
+    // Once this many blocks are live every step frees one
+    max_live: usize,
+}
+
+#[cfg(not(target_os = "none"))]
+impl Workload {
+    fn new(seed: u64) -> Self {
+        Workload {
+            rng: XorShift64::new(seed),
+            frees: 6,
+            resizes: 0,
+            max_size: 1 << 16,
+            max_align_exp: 0,
+            max_live: usize::MAX,
+        }
+    }
+
+    fn mix(mut self, frees: u64, resizes: u64) -> Self {
+        self.frees = frees;
+        self.resizes = resizes;
+        self
+    }
+
+    fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    fn max_align_exp(mut self, max_align_exp: u32) -> Self {
+        self.max_align_exp = max_align_exp;
+        self
+    }
+
+    fn max_live(mut self, max_live: usize) -> Self {
+        self.max_live = max_live;
+        self
+    }
+
+    fn next(&mut self, live: usize) -> Step {
+        let roll = self.rng.next_u64();
+        let index = (roll >> 8) as usize % live.max(1);
+        let size = 1 + (roll >> 16) as usize % self.max_size;
+        let pick = roll % 12;
+        if live > 0 && (live >= self.max_live || pick < self.frees) {
+            Step::Free(index)
+        } else if live > 0 && pick < self.frees + self.resizes {
+            Step::Resize(index, size)
+        } else {
+            let align = 1 << ((roll >> 40) % (self.max_align_exp as u64 + 1));
+            Step::Allocate(Layout::from_size_align(size, align).unwrap())
+        }
+    }
+}
+
+// Chooses which free block a request is carved from. by_size holds the chain of free
+// blocks for each size and by_address holds every free block by start address; select
+// returns the start of the chosen block, which must be at least size bytes.
+trait PlacementPolicy: fmt::Debug + Send {
+    fn name(&self) -> &'static str;
+
+    // Whatever the policy carries from one call to the next, so a snapshot can restore it
+    fn state(&self) -> usize {
+        0
+    }
+
+    fn select(
+        &mut self,
+        by_size: &AVLTree<usize, FreeChain>,
+        by_address: &AVLTree<usize, Rc<MemoryBlock>, LargestFree>,
+        size: usize,
+    ) -> Option<usize>;
+}
+
+// Lowest addressed block that fits, keeping allocations packed towards the region start
+#[derive(Debug)]
+struct FirstFit;
+
+impl PlacementPolicy for FirstFit {
+    fn name(&self) -> &'static str {
+        "first-fit"
+    }
+
+    fn select(
+        &mut self,
+        _: &AVLTree<usize, FreeChain>,
+        by_address: &AVLTree<usize, Rc<MemoryBlock>, LargestFree>,
+        size: usize,
+    ) -> Option<usize> {
+        by_address.first_fit(size).map(|(start, _)| *start)
+    }
+}
+
+// Smallest block that fits, so larger blocks stay whole for larger requests
+#[derive(Debug)]
+struct BestFit;
+
+impl PlacementPolicy for BestFit {
+    fn name(&self) -> &'static str {
+        "best-fit"
+    }
+
+    fn select(
+        &mut self,
+        by_size: &AVLTree<usize, FreeChain>,
+        _: &AVLTree<usize, Rc<MemoryBlock>, LargestFree>,
+        size: usize,
+    ) -> Option<usize> {
+        let (_, chain) = by_size.ceiling(&size)?;
+        chain.first().map(|block| block.start())
+    }
+}
+
+// Largest block available, whatever the request
+#[derive(Debug)]
+struct WorstFit;
+
+impl PlacementPolicy for WorstFit {
+    fn name(&self) -> &'static str {
+        "worst-fit"
+    }
+
+    fn select(
+        &mut self,
+        by_size: &AVLTree<usize, FreeChain>,
+        _: &AVLTree<usize, Rc<MemoryBlock>, LargestFree>,
+        size: usize,
+    ) -> Option<usize> {
+        let (largest, chain) = by_size.last()?;
+        if *largest < size {
+            return None;
+        }
+        chain.first().map(|block| block.start())
+    }
+}
+
+// First fit that resumes from the last block it chose, wrapping around the region
+#[derive(Debug, Default)]
+struct NextFit {
+    cursor: usize,
+}
+
+impl PlacementPolicy for NextFit {
+    fn name(&self) -> &'static str {
+        "next-fit"
+    }
+
+    fn state(&self) -> usize {
+        self.cursor
+    }
+
+    fn select(
+        &mut self,
+        _: &AVLTree<usize, FreeChain>,
+        by_address: &AVLTree<usize, Rc<MemoryBlock>, LargestFree>,
+        size: usize,
+    ) -> Option<usize> {
+        let (start, _) = by_address
+            .first_fit_from(&self.cursor, size)
+            .or_else(|| by_address.first_fit(size))?;
+        self.cursor = *start;
+        Some(*start)
+    }
+}
+
+// Rebuilds a shipped policy from its name and state
+fn policy_by_name(name: &str, state: usize) -> Option<Box<dyn PlacementPolicy>> {
+    match name {
+        "first-fit" => Some(Box::new(FirstFit)),
+        "best-fit" => Some(Box::new(BestFit)),
+        "worst-fit" => Some(Box::new(WorstFit)),
+        "next-fit" => Some(Box::new(NextFit { cursor: state })),
+        _ => None,
+    }
+}
+
+// Buddy allocator over the user region. Every free block is a power of two in size and
+// aligned to its own size, so the buddy of a block is always found by flipping the bit
+// for its size in its address. free_addresses owns the free blocks by start address, and
+// can find the lowest addressed block of a given size; memory_tree keeps a chain of them
+// per size class. Handed out blocks move to used_blocks, keyed by start
+// address, until freed. Which free block a request is split from is up to the policy.
+#[derive(Debug)]
+struct Allocator {
+    memory_tree: AVLTree<usize, FreeChain>,
+    free_addresses: AVLTree<usize, Rc<MemoryBlock>, LargestFree>,
+    used_blocks: AVLTree<usize, MemoryBlock>,
+    config: AllocatorConfig,
+    policy: Box<dyn PlacementPolicy>,
+    counters: AllocCounters,
+    // Set while a trace is being recorded, see start_trace
+    trace: Option<TraceRecorder>,
+    // Bytes in use per owner, and the most each owner with a quota may hold
+    owner_bytes: AVLTree<OwnerTag, usize>,
+    quotas: AVLTree<OwnerTag, usize>,
+    // Ranges inside the region that are never handed out, start to length
+    reserved: AVLTree<usize, usize>,
+    // Starts of blocks freed and not handed out again since, so a second free of one
+    // is told apart from a free of an address that was never allocated. A start is
+    // forgotten once it is coalesced into the free block below it or handed out as part
+    // of another block, so there are never more of them than free blocks.
+    freed_starts: AVLTree<usize, ()>,
+}
+
+// The Rc and Weak links between free blocks never leave the allocator: every clone of
+// a free block's Rc is owned by these trees, so they all move to another thread together
+unsafe impl Send for Allocator {}
+
+// Who an allocation is for, so leak_report can say who is holding memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum OwnerTag {
+    Subsystem(u32),
+    Process(u32),
+    Caller(&'static str),
+}
+
+impl fmt::Display for OwnerTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OwnerTag::Subsystem(id) => write!(f, "subsystem {}", id),
+            OwnerTag::Process(pid) => write!(f, "process {}", pid),
+            OwnerTag::Caller(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// Live blocks of one owner, from Allocator::leak_report. Untagged blocks are grouped
+// under None.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LeakEntry {
+    owner: Option<OwnerTag>,
+    blocks: usize,
+    bytes: usize,
+}
+
+// Largest holders first
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LeakReport {
+    entries: Vec<LeakEntry>,
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            match &entry.owner {
+                Some(owner) => write!(f, "{}", owner)?,
+                None => write!(f, "untagged")?,
+            }
+            writeln!(f, ": {} blocks, {} bytes", entry.blocks, entry.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// What one Allocator::compact did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct CompactionReport {
+    moved_blocks: usize,
+    moved_bytes: usize,
+    largest_free_before: usize,
+    largest_free_after: usize,
+}
+
+impl fmt::Display for CompactionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "moved {} blocks ({} bytes), largest free block {} -> {}",
+            self.moved_blocks, self.moved_bytes, self.largest_free_before, self.largest_free_after
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct AllocCounters {
+    allocations: usize,
+    failures: usize,
+    frees: usize,
+}
+
+// A snapshot of Allocator, from Allocator::stats
+#[derive(Debug, Clone, PartialEq)]
+struct AllocatorStats {
+    // The region less its reserved ranges
+    total_bytes: usize,
+    free_bytes: usize,
+    used_bytes: usize,
+    // (order, free blocks of 2^order bytes) for every order the allocator uses
+    free_blocks_per_order: Vec<(u32, usize)>,
+    largest_free_block: usize,
+    // 1 - largest free run / free bytes, where a run is free blocks that sit next to
+    // each other. Zero when all free memory is contiguous, near one when it is scattered.
+    fragmentation: f64,
+    counters: AllocCounters,
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes free, {} used, largest free block {}, fragmentation {:.3}, {} allocations, {} failures, {} frees",
+            self.free_bytes,
+            self.total_bytes,
+            self.used_bytes,
+            self.largest_free_block,
+            self.fragmentation,
+            self.counters.allocations,
+            self.counters.failures,
+            self.counters.frees
+        )
+    }
+}
+
+impl Allocator {
+    pub fn new() -> Self {
+        Self::with_config(AllocatorConfig::default()).expect("default allocator config is valid")
+    }
+
+    pub fn with_config(config: AllocatorConfig) -> Result<Self, AllocError> {
+        let mut rng = Self::layout_rng(&config);
+        let mut allocator = Self::empty(config)?;
+        allocator.add_free_range(allocator.config.region_start, allocator.config.region_size, &mut rng);
+        Ok(allocator)
+    }
+
+    // An allocator over the span of available, holding only the memory that is listed
+    // as available and not reserved. Everything else in the span, such as MMIO holes,
+    // the kernel image or firmware tables, is recorded as reserved and never handed out.
+    // Available ranges are trimmed inwards to the minimum block and reserved ranges
+    // outwards. config supplies everything but the region.
+    pub fn from_ranges(
+        available: &[(usize, usize)],
+        reserved: &[(usize, usize)],
+        config: AllocatorConfig,
+    ) -> Result<Self, AllocError> {
+        // The rest of the config is checked once the region is known, but the ranges have
+        // to be trimmed to the minimum block before then
+        let min_block = 1usize
+            .checked_shl(config.min_block_exp)
+            .ok_or(AllocError::InvalidConfig("block exponents out of range"))?;
+        let range_end =
+            |start: usize, len: usize| start.checked_add(len).ok_or(AllocError::RangeOverflow { start, len });
+        let mut usable = Vec::new();
+        for &(start, len) in available {
+            // Shrunk to whole blocks; a start too close to the top to round up holds none
+            let end = range_end(start, len)? & !(min_block - 1);
+            let start = start.checked_next_multiple_of(min_block).unwrap_or(end);
+            if start < end {
+                usable.push((start, end));
+            }
+        }
+        usable.sort();
+        for &(start, len) in reserved {
+            // Grown to whole blocks, up to the top of the address space if need be
+            let end = range_end(start, len)?;
+            let hole = (start & !(min_block - 1), end.checked_next_multiple_of(min_block).unwrap_or(usize::MAX));
+            usable = usable
+                .into_iter()
+                .flat_map(|(start, end)| [(start, end.min(hole.0)), (start.max(hole.1), end)])
+                .filter(|(start, end)| start < end)
+                .collect();
+        }
+
+        let (first, last) = match (usable.first(), usable.last()) {
+            (Some(first), Some(last)) => (first.0, last.1),
+            _ => return Err(AllocError::InvalidConfig("no usable memory in the available ranges")),
+        };
+        let mut rng = Self::layout_rng(&config);
+        let mut allocator = Self::empty(config.region(first, last - first))?;
+
+        let mut covered = first;
+        for (start, end) in usable {
+            if start > covered {
+                allocator.reserved.insert(covered, start - covered);
+            }
+            // Overlapping available ranges are only added once
+            let start = start.max(covered);
+            if start < end {
+                allocator.add_free_range(start, end - start, &mut rng);
+                covered = end;
+            }
+        }
+        Ok(allocator)
+    }
+
+    fn layout_rng(config: &AllocatorConfig) -> XorShift64 {
+        XorShift64::new(config.seed.unwrap_or_else(fresh_seed))
+    }
+
+    // Cuts [start, start + size) into free blocks as the config's layout says
+    fn add_free_range(&mut self, start: usize, size: usize, rng: &mut XorShift64) {
+        let min_exp = self.config.min_block_exp;
+        let max_exp = self.config.max_block_exp;
+
+        let mut remaining_memory = size;
+        let mut current_address = start;
+
+        while remaining_memory > 0 {
+            let mut block_size_exp = min_exp;
+            // A block may be no larger than the alignment of its start address, or it
+            // would not have a buddy to coalesce with
+            let largest_exp = max_exp
+                .min(usize::BITS - 1 - remaining_memory.leading_zeros())
+                .min(current_address.trailing_zeros());
+
+            match self.config.layout {
+                RegionLayout::LargestAligned => block_size_exp = largest_exp,
+                RegionLayout::Random => {
+                    let exp_range = min_exp..=largest_exp;
+                    let exp_weights = exp_range
+                        .clone()
+                        .map(|x| (max_exp - x) as f64)
+                        .collect::<Vec<_>>();
+                    let sum_weights = exp_weights.iter().sum::<f64>();
+
+                    let random_weight: f64 = rng.next_f64() * sum_weights;
+
+                    let mut cumulative_weight = 0.0;
+                    for (exp, &weight) in exp_range.zip(exp_weights.iter()) {
+                        cumulative_weight += weight;
+                        if cumulative_weight >= random_weight {
+                            block_size_exp = exp;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let block_size = 1 << block_size_exp;
+            self.insert_free(MemoryBlock::from_range(current_address, block_size, true));
+            current_address += block_size;
+            remaining_memory -= block_size;
+        }
+    }
+
+    // An allocator for the configured region that tracks no blocks at all yet
+    fn empty(config: AllocatorConfig) -> Result<Self, AllocError> {
+        config.validate()?;
+
+        Ok(Allocator {
+            memory_tree: AVLTree::<usize, FreeChain>::new(),
+            free_addresses: AVLTree::<usize, Rc<MemoryBlock>, LargestFree>::new(),
+            used_blocks: AVLTree::<usize, MemoryBlock>::new(),
+            config,
+            policy: Box::new(BestFit),
+            counters: AllocCounters::default(),
+            trace: None,
+            owner_bytes: AVLTree::new(),
+            quotas: AVLTree::new(),
+            reserved: AVLTree::new(),
+            freed_starts: AVLTree::new(),
+        })
+    }
+
+    pub fn with_policy(mut self, policy: Box<dyn PlacementPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn min_block_size(&self) -> usize {
+        1 << self.config.min_block_exp
+    }
+
+    fn max_block_size(&self) -> usize {
+        1 << self.config.max_block_exp
+    }
+
+    // Bytes actually handed out for size: whole minimum blocks, not a whole order
+    fn rounded_size(&self, size: usize) -> usize {
+        (size + self.min_block_size() - 1) & !(self.min_block_size() - 1)
+    }
+
+    fn insert_free(&mut self, block: MemoryBlock) {
+        let start = block.start();
+        let block = Rc::new(block);
+        self.memory_tree
+            .entry(block.size())
+            .or_insert_with(FreeChain::default)
+            .push(&block);
+        self.free_addresses.insert(start, block);
+    }
+
+    // Takes the free block of size bytes starting at address off its chain, if present
+    fn take_free(&mut self, size: usize, address: usize) -> Option<MemoryBlock> {
+        if self.free_addresses.search(&address).map(|block| block.size()) != Some(size) {
+            return None;
+        }
+        let block = self.free_addresses.remove(&address)?;
+
+        let chain = self.memory_tree.get_mut(&size)?;
+        chain.unlink(&block);
+        if chain.len == 0 {
+            self.memory_tree.remove(&size);
+        }
+        Rc::try_unwrap(block).ok()
+    }
+
+    pub fn allocate_block(&mut self, size: usize) -> Option<(usize, usize)> {
+        let layout = Layout::from_size_align(size, 1).ok()?;
+        let start = self.allocate(layout).ok()?;
+        Some((start, start + self.rounded_size(size) - 1))
+    }
+
+    // Every block is aligned to its own size, so a block at least as large as the
+    // alignment always starts on an aligned address and there is no leading slack to
+    // give back. The trailing slack past the rounded size goes back to the free lists.
+    pub fn allocate(&mut self, layout: Layout) -> Result<usize, AllocError> {
+        self.allocate_as(layout, None)
+    }
+
+    // Like allocate, but charges the block to tag and fails once tag would go over
+    // its quota
+    pub fn allocate_tagged(&mut self, layout: Layout, tag: OwnerTag) -> Result<usize, AllocError> {
+        if let Err(err) = self.check_quota(Some(tag), self.rounded_size(layout.size())) {
+            self.counters.failures += 1;
+            return Err(err);
+        }
+        self.allocate_as(layout, Some(tag))
+    }
+
+    pub fn allocate_block_tagged(&mut self, size: usize, tag: OwnerTag) -> Result<(usize, usize), AllocError> {
+        let layout = match Layout::from_size_align(size, 1) {
+            Ok(layout) => layout,
+            Err(_) => {
+                self.counters.failures += 1;
+                return Err(AllocError::TooLarge(size));
+            }
+        };
+        let start = self.allocate_tagged(layout, tag)?;
+        Ok((start, start + self.rounded_size(size) - 1))
+    }
+
+    fn allocate_as(&mut self, layout: Layout, owner: Option<OwnerTag>) -> Result<usize, AllocError> {
+        let result = self.place(layout, owner);
+        match result {
+            Ok(start) => {
+                self.counters.allocations += 1;
+                if let Some(trace) = &mut self.trace {
+                    trace.alloc(start, layout);
+                }
+            }
+            Err(_) => self.counters.failures += 1,
+        }
+        result
+    }
+
+    fn place(&mut self, layout: Layout, owner: Option<OwnerTag>) -> Result<usize, AllocError> {
+        let size = self.rounded_size(layout.size());
+        if layout.size() == 0 || size > self.max_block_size() || layout.align() > self.max_block_size() {
+            return Err(AllocError::InvalidLayout(layout));
+        }
+
+        let block_size = size.next_power_of_two().max(layout.align());
+        let start = self
+            .policy
+            .select(&self.memory_tree, &self.free_addresses, block_size)
+            .ok_or(AllocError::OutOfMemory(layout))?;
+        let fit = self.free_addresses.search(&start).map_or(0, |block| block.size());
+        let mut block = self
+            .take_free(fit, start)
+            .ok_or(AllocError::OutOfMemory(layout))?;
+
+        let slack = block.split_off(size);
+        if !slack.pages.is_empty() {
+            self.release(slack);
+        }
+
+        block.free = false;
+        block.add_ref();
+        block.owner = owner;
+        block.align = layout.align();
+        self.charge(owner, size);
+        self.freed_starts.remove(&start);
+        self.used_blocks.insert(start, block);
+        Ok(start)
+    }
+
+    // Drops one reference to the block at start. The block only goes back to the free
+    // lists once the last reference is gone.
+    pub fn free_block(&mut self, start: usize) -> Result<(), AllocError> {
+        let remaining = match self.used_blocks.get_mut(&start) {
+            Some(block) => block.remove_ref()?,
+            None => return Err(self.missing_block(start)),
+        };
+
+        if remaining == 0 {
+            let mut block = self.used_blocks.remove(&start).unwrap();
+            self.credit(block.owner.take(), block.size());
+            // Recorded first, so release can forget it if the block coalesces downwards
+            self.freed_starts.insert(start, ());
+            self.release(block);
+            if let Some(trace) = &mut self.trace {
+                trace.free(start);
+            }
+        }
+        self.counters.frees += 1;
+        Ok(())
+    }
+
+    // Adds a reference to the block at start, e.g. for a page mapped into a second
+    // address space, and returns the new count
+    pub fn share_block(&mut self, start: usize) -> Result<usize, AllocError> {
+        match self.used_blocks.get_mut(&start) {
+            Some(block) => Ok(block.add_ref()),
+            None => Err(self.missing_block(start)),
+        }
+    }
+
+    pub fn ref_count(&self, start: usize) -> Option<usize> {
+        self.used_blocks.search(&start).map(|block| block.refs)
+    }
+
+    // Copy on write: gives the caller a block of its own to write to. A block nobody
+    // else holds is returned as is; a shared one is copied into a new block with
+    // copy(from, to, len) and the caller's reference to the original is dropped.
+    pub fn make_unique<F>(&mut self, start: usize, copy: F) -> Result<usize, AllocError>
+    where
+        F: FnOnce(usize, usize, usize),
+    {
+        let (size, refs, owner, align) = match self.used_blocks.search(&start) {
+            Some(block) => (block.size(), block.refs, block.owner, block.align),
+            None => return Err(self.missing_block(start)),
+        };
+        if refs == 1 {
+            return Ok(start);
+        }
+
+        self.check_quota(owner, size)?;
+        let layout = Layout::from_size_align(size, align.max(self.min_block_size())).unwrap();
+        let copy_start = self.allocate_as(layout, owner)?;
+        copy(start, copy_start, size);
+        self.free_block(start)?;
+        Ok(copy_start)
+    }
+
+    // The error for an address that has no block in used_blocks
+    fn missing_block(&self, start: usize) -> AllocError {
+        if self.freed_starts.search(&start).is_some() {
+            AllocError::DoubleFree(start)
+        } else {
+            AllocError::UnknownAddress(start)
+        }
+    }
+
+    // Resizes the allocation at start, returning where it now lives. Shrinking gives the
+    // tail back and growing takes the free pages right after the block, both in place.
+    // Only if those pages are taken, or the block is shared, does the allocation move;
+    // the allocator tracks addresses only, so copy(from, to, len) is left to move the
+    // contents.
+    pub fn reallocate<F>(&mut self, start: usize, old: Layout, new_size: usize, copy: F) -> Result<usize, AllocError>
+    where
+        F: FnOnce(usize, usize, usize),
+    {
+        // A move allocates and frees underneath, which the trace records as one realloc
+        let trace = self.trace.take();
+        let result = self.resize(start, old, new_size, copy);
+        self.trace = trace;
+
+        if let (Some(trace), Ok(new_start)) = (&mut self.trace, &result) {
+            if *new_start != start && self.used_blocks.search(&start).is_some() {
+                // A shared block stays put for its other holders, so the caller's copy
+                // is a new allocation
+                trace.alloc(*new_start, Layout::from_size_align(new_size, old.align()).unwrap());
+            } else {
+                trace.realloc(start, *new_start, new_size);
+            }
+        }
+        result
+    }
+
+    fn resize<F>(&mut self, start: usize, old: Layout, new_size: usize, copy: F) -> Result<usize, AllocError>
+    where
+        F: FnOnce(usize, usize, usize),
+    {
+        let new_layout = Layout::from_size_align(new_size, old.align()).map_err(|_| AllocError::InvalidLayout(old))?;
+        let size = self.rounded_size(new_size);
+        if new_size == 0 || size > self.max_block_size() {
+            return Err(AllocError::InvalidLayout(new_layout));
+        }
+
+        let (old_size, shared, owner) = match self.used_blocks.search(&start) {
+            Some(block) => (block.size(), block.refs > 1, block.owner),
+            None => return Err(self.missing_block(start)),
+        };
+
+        // Resizing a shared block in place would resize it for every holder
+        if shared {
+            self.check_quota(owner, size)?;
+            let new_start = self.allocate_as(new_layout, owner)?;
+            copy(start, new_start, old.size().min(new_size));
+            self.free_block(start)?;
+            return Ok(new_start);
+        }
+
+        if size < old_size {
+            let tail = self.used_blocks.get_mut(&start).unwrap().split_off(size);
+            self.credit(owner, tail.size());
+            self.release(tail);
+            return Ok(start);
+        }
+
+        if size > old_size {
+            // Only the growth counts against the quota, wherever the block ends up
+            self.check_quota(owner, size - old_size)?;
+            if let Some(mut grown) = self.claim_range(start + old_size, size - old_size) {
+                let block = self.used_blocks.get_mut(&start).unwrap();
+                grown.free = false;
+                block.pages.append(&mut grown.pages);
+                self.charge(owner, size - old_size);
+                return Ok(start);
+            }
+
+            let new_start = self.allocate_as(new_layout, owner)?;
+            copy(start, new_start, old.size());
+            self.free_block(start)?;
+            return Ok(new_start);
+        }
+
+        Ok(start)
+    }
+
+    // Takes [start, start + len) out of circulation for good, e.g. for a framebuffer
+    // found after the allocator was built. The range is widened to whole minimum
+    // blocks and clipped to the region, and every page of it has to be free.
+    pub fn reserve(&mut self, start: usize, len: usize) -> Result<(), AllocError> {
+        let min_block = self.min_block_size();
+        let region_end = self.config.region_start + self.config.region_size;
+        // Both ends are taken from the range as given, then widened to whole blocks and
+        // cut down to the region; region_end is block aligned, so rounding it can't overflow
+        let end = start.checked_add(len).ok_or(AllocError::RangeOverflow { start, len })?;
+        let end = end.min(region_end).next_multiple_of(min_block);
+        let start = (start & !(min_block - 1)).max(self.config.region_start);
+        if start >= end {
+            return Ok(());
+        }
+
+        // Already reserved pages are skipped, everything between them is claimed
+        let mut pieces = Vec::new();
+        let mut address = start;
+        while address < end {
+            match self.reserved_at(address) {
+                Some((_, reserved_end)) => address = reserved_end,
+                None => {
+                    let next = self.reserved.ceiling(&address).map_or(end, |(next, _)| (*next).min(end));
+                    pieces.push((address, next - address));
+                    address = next;
+                }
+            }
+        }
+        for &(piece, size) in &pieces {
+            let last = self.used_blocks.floor(&(piece + size - 1));
+            if last.is_some_and(|(used, block)| used + block.size() > piece) {
+                return Err(AllocError::RangeInUse { start, size: end - start });
+            }
+        }
+
+        for (piece, size) in pieces {
+            // Nothing in the piece is used or reserved, so all of it is on the free lists
+            self.claim_range(piece, size).expect("unused range is free");
+            self.reserved.insert(piece, size);
+        }
+        Ok(())
+    }
+
+    // The reserved range holding address, as (start, end)
+    fn reserved_at(&self, address: usize) -> Option<(usize, usize)> {
+        let (start, len) = self.reserved.floor(&address)?;
+        Some((*start, start + len)).filter(|&(_, end)| address < end)
+    }
+
+    pub fn reserved_bytes(&self) -> usize {
+        self.reserved.iter().map(|(_, len)| len).sum()
+    }
+
+    // Makes sure no free or used block reaches into a reserved range
+    pub fn check_reservations(&self) -> Result<(), AllocError> {
+        let free = self.free_addresses.iter().map(|(start, block)| (*start, block.size()));
+        let used = self.used_blocks.iter().map(|(start, block)| (*start, block.size()));
+        for (block, size) in free.chain(used) {
+            let overlap = self.reserved_at(block).map(|(reserved, _)| reserved).or_else(|| {
+                self.reserved
+                    .range(block..block + size)
+                    .next()
+                    .map(|(reserved, _)| *reserved)
+            });
+            if let Some(reserved) = overlap {
+                return Err(AllocError::ReservedOverlap { block, reserved });
+            }
+        }
+        Ok(())
+    }
+
+    // Takes [start, start + size) out of the free lists if every page of it is free.
+    // Free blocks that stick out of either end of the range are cut down, and the
+    // parts outside the range go straight back.
+    fn claim_range(&mut self, start: usize, size: usize) -> Option<MemoryBlock> {
+        let end = start + size;
+
+        let mut covering = Vec::new();
+        let mut address = start;
+        while address < end {
+            let (block_start, block) = self.free_addresses.floor(&address)?;
+            if block_start + block.size() <= address {
+                return None;
+            }
+            covering.push((*block_start, block.size()));
+            address = block_start + block.size();
+        }
+
+        let mut claimed = MemoryBlock::new(Vec::new(), true, None, 0);
+        let mut outside = Vec::new();
+        for (block_start, block_size) in covering {
+            let mut block = self.take_free(block_size, block_start)?;
+            if block_start >= start {
+                self.freed_starts.remove(&block_start);
+            }
+            if block_start < start {
+                let inside = block.split_off(start - block_start);
+                outside.push(block);
+                block = inside;
+            }
+            if block.start() + block.size() > end {
+                outside.push(block.split_off(end - block.start()));
+            }
+            claimed.pages.append(&mut block.pages);
+        }
+
+        for block in outside {
+            self.release(block);
+        }
+        Some(claimed)
+    }
+
+    // Returns a run of pages to the free lists. The run is cut into the largest
+    // aligned power of two blocks it holds, and each is coalesced with its buddy for
+    // as long as the buddy is free.
+    fn release(&mut self, mut run: MemoryBlock) {
+        run.free = true;
+
+        while !run.pages.is_empty() {
+            let start = run.start();
+            let piece_exp = self
+                .config
+                .max_block_exp
+                .min(start.trailing_zeros())
+                .min(usize::BITS - 1 - run.size().leading_zeros());
+            let rest = run.split_off(1 << piece_exp);
+            let mut block = run;
+            run = rest;
+
+            while block.size() < self.max_block_size() {
+                match self.take_free(block.size(), block.buddy_address()) {
+                    Some(buddy) => {
+                        // The upper buddy's start ends up inside the merged block
+                        self.freed_starts.remove(&block.start().max(buddy.start()));
+                        block = block.merge(buddy);
+                    }
+                    None => break,
+                }
+            }
+
+            self.insert_free(block);
+        }
+    }
+
+    // Slides live blocks toward the start of the region, lowest first, so the free
+    // space between them runs together and coalesces into larger blocks. Only blocks
+    // owned by one of movable are moved, and only while nobody else shares them, since
+    // their holder is the one that has to follow the move. Blocks keep their order: each
+    // goes to the lowest free address that keeps its alignment between the end of the
+    // block handled before it and where it is now, so every free block is looked at about
+    // once. relocate(from, to, len) is called once it is there, to move the contents and
+    // update pointers. The two ranges may overlap, so the contents have to be moved as
+    // by ptr::copy.
+    pub fn compact<F>(&mut self, movable: &[OwnerTag], mut relocate: F) -> CompactionReport
+    where
+        F: FnMut(usize, usize, usize),
+    {
+        let mut report = CompactionReport {
+            largest_free_before: self.memory_tree.last().map_or(0, |(size, _)| *size),
+            ..CompactionReport::default()
+        };
+        let candidates: Vec<usize> = self
+            .used_blocks
+            .iter()
+            .filter(|(_, block)| block.refs == 1 && block.owner.is_some_and(|owner| movable.contains(&owner)))
+            .map(|(start, _)| *start)
+            .collect();
+
+        // Where the block handled last ends. Moves only ever free space above it.
+        let mut cursor = self.config.region_start;
+        for start in candidates {
+            let block = self.used_blocks.search(&start).unwrap();
+            let (size, align) = (block.size(), block.align.max(self.min_block_size()));
+            let target = match self.lowest_fit(cursor, start, size, align) {
+                Some(target) => target,
+                None => {
+                    cursor = start + size;
+                    continue;
+                }
+            };
+            cursor = target + size;
+
+            // Giving the pages back first lets the block slide into a range that
+            // overlaps where it is now
+            let mut block = self.used_blocks.remove(&start).unwrap();
+            let pages = core::mem::take(&mut block.pages);
+            // Freed as far as the caller can tell, unless the block lands over it
+            self.freed_starts.insert(start, ());
+            self.release(MemoryBlock::new(pages, true, None, 0));
+            block.pages = self.claim_range(target, size).expect("target range is free").pages;
+            self.used_blocks.insert(target, block);
+
+            relocate(start, target, size);
+            if let Some(trace) = &mut self.trace {
+                trace.moved(start, target);
+            }
+            report.moved_blocks += 1;
+            report.moved_bytes += size;
+        }
+
+        report.largest_free_after = self.memory_tree.last().map_or(0, |(size, _)| *size);
+        report
+    }
+
+    // Lowest address aligned to align, from from up to the used block at start, where
+    // its size bytes would all be free, counting the block's own pages as free. from has
+    // to be where a block ends, so no free block straddles it.
+    fn lowest_fit(&self, from: usize, start: usize, size: usize, align: usize) -> Option<usize> {
+        let below = self.free_addresses.range(from..start);
+        let above = self.free_addresses.range(start + size..);
+        let pieces = below
+            .map(|(start, block)| (*start, block.size()))
+            .chain(core::iter::once((start, size)))
+            .chain(above.map(|(start, block)| (*start, block.size())));
+
+        let mut run = (0, 0);
+        for (piece, len) in pieces {
+            run = if run.0 + run.1 == piece { (run.0, run.1 + len) } else { (piece, len) };
+            let target = (run.0 + align - 1) & !(align - 1);
+            if target >= start {
+                return None;
+            }
+            if target + size <= run.0 + run.1 {
+                return Some(target);
+            }
+        }
+        None
+    }
+
+    // Caps the bytes tag may hold at once; None lifts the cap. Blocks already held
+    // are kept even if they are over the new limit.
+    pub fn set_quota(&mut self, tag: OwnerTag, limit: Option<usize>) {
+        match limit {
+            Some(limit) => self.quotas.insert(tag, limit),
+            None => {
+                self.quotas.remove(&tag);
+            }
+        }
+    }
+
+    pub fn owner_bytes(&self, tag: OwnerTag) -> usize {
+        self.owner_bytes.search(&tag).copied().unwrap_or(0)
+    }
+
+    fn check_quota(&self, owner: Option<OwnerTag>, requested: usize) -> Result<(), AllocError> {
+        let tag = match owner {
+            Some(tag) => tag,
+            None => return Ok(()),
+        };
+        match self.quotas.search(&tag) {
+            Some(&limit) if self.owner_bytes(tag) + requested > limit => Err(AllocError::QuotaExceeded {
+                tag,
+                used: self.owner_bytes(tag),
+                limit,
+                requested,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn charge(&mut self, owner: Option<OwnerTag>, bytes: usize) {
+        if let Some(tag) = owner {
+            self.owner_bytes.entry(tag).and_modify(|held| *held += bytes).or_insert(bytes);
+        }
+    }
+
+    fn credit(&mut self, owner: Option<OwnerTag>, bytes: usize) {
+        if let Some(tag) = owner {
+            let held = self.owner_bytes.get_mut(&tag).unwrap();
+            *held -= bytes;
+            if *held == 0 {
+                self.owner_bytes.remove(&tag);
+            }
+        }
+    }
+
+    // Every live block grouped by owner, so whoever is holding memory stands out
+    pub fn leak_report(&self) -> LeakReport {
+        let mut groups: AVLTree<Option<OwnerTag>, (usize, usize)> = AVLTree::new();
+        for (_, block) in self.used_blocks.iter() {
+            let group = groups.entry(block.owner).or_insert((0, 0));
+            group.0 += 1;
+            group.1 += block.size();
+        }
+
+        let mut entries: Vec<LeakEntry> = groups
+            .iter()
+            .map(|(owner, (blocks, bytes))| LeakEntry {
+                owner: *owner,
+                blocks: *blocks,
+                bytes: *bytes,
+            })
+            .collect();
+        entries.sort_by_key(|entry| Reverse(entry.bytes));
+        LeakReport { entries }
+    }
+
+    // Records every allocate, free and reallocate from now on, until take_trace
+    pub fn start_trace(&mut self) {
+        self.trace = Some(TraceRecorder::new(self.config.region_start, self.config.region_size));
+    }
+
+    pub fn take_trace(&mut self) -> Option<trace::Trace> {
+        self.trace.take().map(TraceRecorder::finish)
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let free_bytes = self.free_bytes();
+        let largest_run = self.largest_free_run();
+        let fragmentation = if free_bytes == 0 {
+            0.0
+        } else {
+            1.0 - largest_run as f64 / free_bytes as f64
+        };
+
+        AllocatorStats {
+            total_bytes: self.config.region_size - self.reserved_bytes(),
+            free_bytes,
+            used_bytes: self.used_bytes(),
+            free_blocks_per_order: (self.config.min_block_exp..=self.config.max_block_exp)
+                .map(|order| (order, self.memory_tree.search(&(1 << order)).map_or(0, |chain| chain.len)))
+                .collect(),
+            largest_free_block: self.memory_tree.last().map_or(0, |(size, _)| *size),
+            fragmentation,
+            counters: self.counters,
+        }
+    }
+
+    // Longest stretch of free blocks that follow each other with no gap
+    fn largest_free_run(&self) -> usize {
+        let mut largest = 0;
+        let mut run = (0, 0);
+        for (start, block) in self.free_addresses.iter() {
+            run = if run.0 + run.1 == *start {
+                (run.0, run.1 + block.size())
+            } else {
+                (*start, block.size())
+            };
+            largest = largest.max(run.1);
+        }
+        largest
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.memory_tree.iter().map(|(size, chain)| size * chain.len).sum()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_blocks.iter().map(|(_, block)| block.size()).sum()
+    }
+
+    pub fn free_block_count(&self) -> usize {
+        self.memory_tree.iter().map(|(_, chain)| chain.len).sum()
+    }
+
+    // Walks every chain link by link. Each link has to lead to a live free block of the
+    // chain's size that free_addresses owns and that links back to the block before it,
+    // each chain has to be as long as it claims, and no two free blocks may overlap.
+    pub fn check_free_lists(&self) -> Result<(), AllocError> {
+        let mut blocks = Vec::new();
+
+        for (size, chain) in self.memory_tree.iter() {
+            let corrupt = |address| AllocError::CorruptFreeList { size: *size, address };
+            let mut link = chain.head.clone();
+            let mut previous: Option<Rc<MemoryBlock>> = None;
+            let mut len = 0;
+
+            while let Some(weak) = link {
+                let previous_start = previous.as_ref().map_or(0, |block| block.start());
+                let block = weak.upgrade().ok_or(corrupt(previous_start))?;
+                let owned = self
+                    .free_addresses
+                    .search(&block.start())
+                    .is_some_and(|owner| Rc::ptr_eq(owner, &block));
+                let back = block.prev_block.borrow().as_ref().map(Weak::as_ptr);
+                let linked_back = back == previous.as_ref().map(Rc::as_ptr);
+                link = block.next_block.borrow().clone();
+                let next_size = if link.is_some() { *size } else { 0 };
+                if !owned
+                    || !linked_back
+                    || !block.free
+                    || block.size() != *size
+                    || block.next_block_size.get() != next_size
+                {
+                    return Err(corrupt(block.start()));
+                }
+
+                blocks.push((block.start(), block.size()));
+                previous = Some(block);
+                len += 1;
+            }
+
+            if len != chain.len {
+                return Err(corrupt(previous.map_or(0, |block| block.start())));
+            }
+        }
+
+        if blocks.len() != self.free_addresses.iter().count() {
+            return Err(AllocError::CorruptFreeList { size: 0, address: 0 });
+        }
+        blocks.sort();
+        for pair in blocks.windows(2) {
+            let ((start, size), (next_start, next_size)) = (pair[0], pair[1]);
+            if start + size > next_start {
+                return Err(AllocError::CorruptFreeList { size: next_size, address: next_start });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Example usage
+#[cfg(not(target_os = "none"))]
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 2 && args[1] == "replay" {
+        if let Err(err) = trace::replay_main(&args[2], &args[3..]) {
+            println!("Replay failed: {}", err);
+        }
+        return;
+    }
+    if args.len() > 2 && args[1] == "record" {
+        let operations = args.get(3).and_then(|arg| arg.parse().ok()).unwrap_or(10_000);
+        if let Err(err) = trace::record_main(&args[2], operations) {
+            println!("Recording failed: {}", err);
+        }
+        return;
+    }
+    if args.len() > 1 && args[1] == "bench" {
+        let threads = args.get(2).and_then(|arg| arg.parse().ok()).unwrap_or(8);
+        if let Err(err) = magazine::benchmark(threads, 50_000) {
+            println!("Benchmark failed: {}", err);
+        }
+        return;
+    }
+    if args.len() > 1 && args[1] == "map" {
+        // A few allocations so the map has something to show
+        let mut allocator = Allocator::new();
+        for size in [4096, 100_000, 3 << 20, 12_345] {
+            let _ = allocator.allocate(Layout::from_size_align(size, 1).unwrap());
+        }
+        let out = &mut std::io::stdout();
+        let written = match args.get(2).map(String::as_str) {
+            Some("svg") => memory_map::write_svg(&allocator, out),
+            Some("csv") => memory_map::write_csv(&allocator, out),
+            _ => memory_map::write_ascii(&allocator, out),
+        };
+        if let Err(err) = written {
+            println!("Could not draw the memory map: {}", err);
+        }
+        return;
+    }
+    if args.len() > 1 && args[1] == "stress" {
+        let threads = args.get(2).and_then(|arg| arg.parse().ok()).unwrap_or(4);
+        match locked::stress(threads, 20_000) {
+            Ok(report) => println!("{}", report),
+            Err(err) => println!("Stress test failed: {}", err),
+        }
+        return;
+    }
+
+    let mut allocator = Allocator::new();
+    let requested_size = 4096;
+    let allocated_block = allocator.allocate_block(requested_size);
+    match allocated_block {
+        Some((start, end)) => {
+            println!(
+                "Allocated block for size {}: start = 0x{:x}, end = 0x{:x}",
+                requested_size, start, end
+            );
+            println!("{}", allocator.stats());
+            if let Err(err) = memory_map::write_ascii(&allocator, &mut std::io::stdout()) {
+                println!("Could not draw the memory map: {}", err);
+            }
+            if let Err(err) = allocator.check_free_lists() {
+                println!("Free lists are inconsistent: {}", err);
+            }
+            if let Err(err) = allocator.free_block(start) {
+                println!("Free failed: {}", err);
+            }
+        }
+        None => {
+            println!("No suitable block found for size {}", requested_size);
+        }
+    }
+
+    let mut slabs = slab::SlabAllocator::new();
+    let object = Layout::from_size_align(24, 8).unwrap();
+    match slabs.allocate(&mut allocator, object) {
+        Ok(address) => {
+            println!(
+                "Allocated {} byte object at 0x{:x}: {} object bytes in {} bytes of slabs",
+                object.size(),
+                address,
+                slabs.object_bytes(),
+                slabs.slab_bytes()
+            );
+            if let Err(err) = slabs.free(&mut allocator, address) {
+                println!("Free failed: {}", err);
+            }
+        }
+        Err(err) => println!("Object allocation failed: {}", err),
+    }
+
+    // A page mapped into a second address space, then written to by the first
+    if let Ok(page) = allocator.allocate(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()) {
+        let shared = allocator.share_block(page).and_then(|_| {
+            println!("Page at 0x{:x} has {:?} references", page, allocator.ref_count(page));
+            allocator.make_unique(page, |from, to, len| {
+                println!("Copying {} bytes from 0x{:x} to 0x{:x}", len, from, to)
+            })
+        });
+        match shared {
+            Ok(copy) => {
+                let _ = allocator.free_block(copy);
+                let _ = allocator.free_block(page);
+            }
+            Err(err) => println!("Copy on write failed: {}", err),
+        }
+    }
+
+    // The filesystem cache is capped at 1 MiB; whatever it and the others still hold
+    // shows up in the leak report, and in the snapshot below
+    let cache = OwnerTag::Subsystem(3);
+    allocator.set_quota(cache, Some(1 << 20));
+    loop {
+        if let Err(err) = allocator.allocate_block_tagged(200_000, cache) {
+            println!("Cache stopped growing: {}", err);
+            break;
+        }
+    }
+    let _ = allocator.allocate_block_tagged(10_000, OwnerTag::Process(1));
+    let _ = allocator.allocate_block_tagged(300, OwnerTag::Caller("log_init"));
+    print!("{}", allocator.leak_report());
+
+    let mut checked = debug::DebugAllocator::new(allocator, debug::HostMemory::new(), debug::DebugConfig::default());
+    if let Ok(address) = checked.allocate(Layout::from_size_align(100, 8).unwrap()) {
+        // One byte past the end of the allocation
+        checked.memory_mut().write(address + 100, &[0]);
+        if let Err(err) = checked.check() {
+            println!("Debug allocator check: {}", err);
+        }
+        if let Err(err) = checked.free(address) {
+            println!("Debug allocator caught: {}", err);
+        }
+    }
+    println!("{}", checked.allocator().stats());
+    let allocator = checked.into_inner();
+
+    let bytes = snapshot::Snapshot::capture(&allocator).to_bytes();
+    match snapshot::Snapshot::from_bytes(&bytes).and_then(|decoded| decoded.restore()) {
+        Ok(restored) => println!(
+            "Restored {} free blocks from a {} byte snapshot",
+            restored.free_block_count(),
+            bytes.len()
+        ),
+        Err(err) => println!("Snapshot restore failed: {}", err),
+    }
+    let json = snapshot::Snapshot::capture(&allocator).to_json();
+    if let Err(err) = snapshot::Snapshot::from_json(&json).and_then(|decoded| decoded.restore()) {
+        println!("JSON snapshot restore failed: {}", err);
+    }
+
+    // The user region with an MMIO hole in the middle and a framebuffer reserved after
+    // the allocator was built; nothing handed out may touch either
+    let available = [(USER_MEM_START, 0x6000000), (USER_MEM_START + 0x8000000, 0x8000000)];
+    let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+    match Allocator::from_ranges(&available, &[], config) {
+        Ok(mut holed) => {
+            let mut live = Vec::new();
+            let result = holed.reserve(USER_MEM_START + 0x9000000, 0x300000).and_then(|_| {
+                for size in (1..=64).map(|i| i * 12_345) {
+                    live.push(holed.allocate(Layout::from_size_align(size, 1).unwrap())?);
+                }
+                holed.check_reservations()
+            });
+            match result {
+                Ok(()) => println!(
+                    "{} blocks allocated around {} reserved bytes",
+                    live.len(),
+                    holed.reserved_bytes()
+                ),
+                Err(err) => println!("Reserved ranges were violated: {}", err),
+            }
+        }
+        Err(err) => println!("Could not build the allocator from ranges: {}", err),
+    }
+
+    // A firmware memory map: conventional memory, the rest of the low 4 GiB below the
+    // PCI hole, and some memory above 4 GiB
+    let memory_map = [(0x1000, 0x9e000), (0x100000, 0x7ff00000), (0x1_0000_0000, 0x4000_0000)];
+    // Blocks of up to 4 MiB, so a driver can get a large buffer in one piece
+    let template = AllocatorConfig::default()
+        .block_exps(MIN_BLOCK_SIZE_EXP, 22)
+        .layout(RegionLayout::LargestAligned);
+    match zone::ZonedAllocator::from_regions(&memory_map, template) {
+        Ok(mut zones) => {
+            let buffer = Layout::from_size_align(64 * 1024, 4096).unwrap();
+            let requests = [
+                ("ISA DMA", zone::ZoneMask::DMA),
+                ("32-bit DMA", zone::ZoneMask::DMA32),
+                ("kernel", zone::ZoneMask::NORMAL),
+                ("direct map only", zone::ZoneMask::only(zone::Zone::Normal)),
+                ("anywhere", zone::ZoneMask::HIGH),
+            ];
+            let mut buffers = Vec::new();
+            for (purpose, mask) in requests {
+                match zones.allocate(buffer, mask) {
+                    Ok(address) => {
+                        println!("{} buffer at 0x{:x}", purpose, address);
+                        buffers.push(address);
+                    }
+                    Err(err) => println!("No {} buffer: {}", purpose, err),
+                }
+            }
+            for (zone, total, free) in zones.zone_usage() {
+                println!("{}: {} of {} bytes free", zone, free, total);
+            }
+            for address in buffers {
+                if let Err(err) = zones.free_block(address) {
+                    println!("Free failed: {}", err);
+                }
+            }
+        }
+        Err(err) => println!("Could not build zones: {}", err),
+    }
+
+    // A heap left with every other page free: plenty of free memory, but no large
+    // block until the garbage collector's pages are slid together
+    let gc = OwnerTag::Subsystem(7);
+    let config = AllocatorConfig::default()
+        .region(USER_MEM_START, 1 << MAX_BLOCK_SIZE_EXP)
+        .layout(RegionLayout::LargestAligned);
+    if let Ok(mut heap) = Allocator::with_config(config) {
+        let page = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let mut objects = Vec::new();
+        while let Ok(address) = heap.allocate_tagged(page, gc) {
+            objects.push(address);
+        }
+        for address in objects.iter().skip(1).step_by(2) {
+            let _ = heap.free_block(*address);
+        }
+        objects = objects.into_iter().step_by(2).collect();
+
+        let large = Layout::from_size_align(heap.free_bytes(), 1).unwrap();
+        if let Err(err) = heap.allocate(large) {
+            println!("Before compaction: {}", err);
+        }
+        let report = heap.compact(&[gc], |from, to, _| {
+            if let Some(object) = objects.iter_mut().find(|object| **object == from) {
+                *object = to;
+            }
+        });
+        println!("Compaction {}", report);
+        match heap.allocate(large) {
+            Ok(address) => println!("{} byte allocation at 0x{:x} after compaction", large.size(), address),
+            Err(err) => println!("Still no room after compaction: {}", err),
+        }
+    }
+
+    let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+    if let Err(err) = tlsf::compare_backends(config, 42, 100_000) {
+        println!("Backend comparison failed: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page() -> Layout {
+        Layout::from_size_align(PAGE_SIZE, 1).unwrap()
+    }
+
+    // free_blocks_per_order, leaving out the orders with no free blocks
+    fn free_orders(allocator: &Allocator) -> Vec<(u32, usize)> {
+        allocator.stats().free_blocks_per_order.into_iter().filter(|&(_, count)| count > 0).collect()
+    }
+
+    #[test]
+    fn buddies_split_down_and_coalesce_back_to_the_largest_block() {
+        // A single free block of the largest order
+        let config = AllocatorConfig::default()
+            .region(USER_MEM_START, 1 << 16)
+            .block_exps(12, 16)
+            .layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::with_config(config).unwrap();
+        assert_eq!(free_orders(&allocator), [(16, 1)]);
+
+        // One page splits off the lowest buddy at every order on the way down
+        let first = allocator.allocate(page()).unwrap();
+        assert_eq!(first, USER_MEM_START);
+        assert_eq!(free_orders(&allocator), [(12, 1), (13, 1), (14, 1), (15, 1)]);
+        allocator.check_free_lists().unwrap();
+
+        // Freed, it finds its buddy free at every order and the block is whole again
+        allocator.free_block(first).unwrap();
+        assert_eq!(free_orders(&allocator), [(16, 1)]);
+
+        // Sixteen pages, freed even pages first so that no two buddies are both free
+        // until half of them are
+        let mut pages = (0..16).map(|_| allocator.allocate(page()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(allocator.free_bytes(), 0);
+        pages.sort_by_key(|start| (start >> 12).reverse_bits());
+        for (freed, start) in pages.into_iter().enumerate() {
+            allocator.free_block(start).unwrap();
+            allocator.check_free_lists().unwrap();
+            if freed < 8 {
+                assert_eq!(free_orders(&allocator), [(12, freed + 1)]);
+            }
+        }
+        assert_eq!(free_orders(&allocator), [(16, 1)]);
+    }
+
+    // Every free block as (start, size), lowest first
+    fn free_layout(allocator: &Allocator) -> Vec<(usize, usize)> {
+        allocator.free_addresses.iter().map(|(start, block)| (*start, block.size())).collect()
+    }
+
+    #[test]
+    fn random_layout_is_reproducible_from_its_seed() {
+        let build = |seed| Allocator::with_config(AllocatorConfig::default().seed(seed)).unwrap();
+        let layout = free_layout(&build(42));
+        assert_eq!(free_layout(&build(42)), layout);
+        assert_ne!(free_layout(&build(43)), layout);
+
+        // Orders other than the largest only show up if the layout is random at all
+        let orders = layout.iter().map(|(_, size)| *size).collect::<std::collections::BTreeSet<_>>();
+        assert!(orders.len() > 1, "{:?}", orders);
+        assert_eq!(layout.iter().map(|(_, size)| size).sum::<usize>(), USER_MEM_SIZE);
+    }
+
+    #[test]
+    fn each_policy_picks_its_own_block_from_the_same_free_lists() {
+        let config = AllocatorConfig::default()
+            .region(USER_MEM_START, 32 * PAGE_SIZE)
+            .block_exps(12, 17)
+            .layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::with_config(config).unwrap();
+        let mut pages = (0..32).map(|_| allocator.allocate(page()).unwrap()).collect::<Vec<_>>();
+        pages.sort();
+        // Leaves 4K at page 1, 16K at 4, 8K at 8, 32K at 16 and 16K at 28 free
+        for index in [1].into_iter().chain(4..10).chain(16..24).chain(28..32) {
+            allocator.free_block(pages[index]).unwrap();
+        }
+        let at = |page: usize| USER_MEM_START + page * PAGE_SIZE;
+        assert_eq!(
+            free_layout(&allocator),
+            [(at(1), 0x1000), (at(4), 0x4000), (at(8), 0x2000), (at(16), 0x8000), (at(28), 0x4000)]
+        );
+
+        let select = |policy: &mut dyn PlacementPolicy, size| {
+            policy.select(&allocator.memory_tree, &allocator.free_addresses, size)
+        };
+        assert_eq!(select(&mut FirstFit, 0x2000), Some(at(4)));
+        assert_eq!(select(&mut BestFit, 0x2000), Some(at(8)));
+        assert_eq!(select(&mut WorstFit, 0x2000), Some(at(16)));
+        // Next fit starts from the block it last chose, which may still fit
+        assert_eq!(select(&mut NextFit { cursor: at(16) }, 0x4000), Some(at(16)));
+        assert_eq!(select(&mut NextFit { cursor: at(17) }, 0x4000), Some(at(28)));
+        // and wraps around to the start of the region once nothing after it does
+        let mut next_fit = NextFit { cursor: at(29) };
+        assert_eq!(select(&mut next_fit, 0x4000), Some(at(4)));
+        assert_eq!(next_fit.cursor, at(4));
+        assert_eq!(select(&mut NextFit { cursor: at(29) }, 0x8000), Some(at(16)));
+
+        let policies: [Box<dyn PlacementPolicy>; 4] =
+            [Box::new(FirstFit), Box::new(BestFit), Box::new(WorstFit), Box::new(NextFit::default())];
+        for mut policy in policies {
+            assert_eq!(select(policy.as_mut(), 0x10000), None, "{}", policy.name());
+        }
+    }
+
+    #[test]
+    fn allocations_start_on_their_alignment() {
+        // The Random layout never carves a block of the largest order
+        let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::with_config(config).unwrap();
+        let mut live = Vec::new();
+        for align_exp in 0..=MAX_BLOCK_SIZE_EXP {
+            let align = 1 << align_exp;
+            // Sizes below, at and above the alignment, as well as between pages
+            for size in [1, PAGE_SIZE + 1, align / 2 + 1, align, 3 * align / 2] {
+                let size = size.clamp(1, 1 << MAX_BLOCK_SIZE_EXP);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let start = allocator.allocate(layout).unwrap();
+                assert_eq!(start % align, 0, "{:?} at {:#x}", layout, start);
+                live.push(start);
+            }
+        }
+        allocator.check_free_lists().unwrap();
+        for start in live {
+            allocator.free_block(start).unwrap();
+        }
+
+        // Nothing is aligned to more than the largest block
+        let layout = Layout::from_size_align(PAGE_SIZE, 2 << MAX_BLOCK_SIZE_EXP).unwrap();
+        assert_eq!(allocator.allocate(layout), Err(AllocError::InvalidLayout(layout)));
+    }
+
+    #[test]
+    fn reallocation_stays_in_place_until_the_next_pages_are_taken() {
+        let config = AllocatorConfig::default()
+            .region(USER_MEM_START, 16 * PAGE_SIZE)
+            .block_exps(12, 16)
+            .layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::with_config(config).unwrap();
+        let pages = |count| Layout::from_size_align(count * PAGE_SIZE, 1).unwrap();
+        let no_copy = |_, _, _| panic!("copied a block resized in place");
+
+        let start = allocator.allocate(pages(4)).unwrap();
+        assert_eq!(allocator.reallocate(start, pages(4), PAGE_SIZE, no_copy), Ok(start));
+        assert_eq!(allocator.used_bytes(), PAGE_SIZE);
+        // The tail went back and coalesced with its buddy, so growing takes it again
+        assert_eq!(allocator.reallocate(start, pages(1), 4 * PAGE_SIZE, no_copy), Ok(start));
+        assert_eq!(allocator.used_bytes(), 4 * PAGE_SIZE);
+        allocator.check_free_lists().unwrap();
+
+        // Once the page after the block is used, growing has to move it
+        let neighbour = allocator.allocate(page()).unwrap();
+        assert_eq!(neighbour, start + 4 * PAGE_SIZE);
+        let mut copied = None;
+        let moved = allocator
+            .reallocate(start, pages(4), 5 * PAGE_SIZE, |from, to, len| copied = Some((from, to, len)))
+            .unwrap();
+        assert_ne!(moved, start);
+        assert_eq!(copied, Some((start, moved, 4 * PAGE_SIZE)));
+        assert_eq!(allocator.free_block(start), Err(AllocError::DoubleFree(start)));
+        assert_eq!(allocator.used_bytes(), 6 * PAGE_SIZE);
+        allocator.check_free_lists().unwrap();
+    }
+
+    #[test]
+    fn free_errors_tell_double_free_from_unknown_address() {
+        // One page, so the freed page is the one handed out next
+        let config = AllocatorConfig::default().region(USER_MEM_START, PAGE_SIZE);
+        let mut allocator = Allocator::with_config(config).unwrap();
+        assert_eq!(
+            allocator.free_block(USER_MEM_START),
+            Err(AllocError::UnknownAddress(USER_MEM_START))
+        );
+
+        let start = allocator.allocate(page()).unwrap();
+        assert_eq!(allocator.free_block(start + 1), Err(AllocError::UnknownAddress(start + 1)));
+        allocator.free_block(start).unwrap();
+        assert_eq!(allocator.free_block(start), Err(AllocError::DoubleFree(start)));
+
+        // Handed out again, the address is live and frees normally
+        assert_eq!(allocator.allocate(page()), Ok(start));
+        allocator.free_block(start).unwrap();
+    }
+
+    #[test]
+    fn freed_starts_are_forgotten_once_coalesced_or_handed_out() {
+        let config = AllocatorConfig::default()
+            .region(USER_MEM_START, 4 * PAGE_SIZE)
+            .block_exps(12, 14)
+            .layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::with_config(config).unwrap();
+        let (low, high) = (allocator.allocate(page()).unwrap(), allocator.allocate(page()).unwrap());
+        assert_eq!(high, low + PAGE_SIZE);
+
+        // With its buddy still used, the upper page stays a block of its own
+        allocator.free_block(high).unwrap();
+        assert_eq!(allocator.free_block(high), Err(AllocError::DoubleFree(high)));
+        // Coalesced into the lower page's block, it no longer starts anything
+        allocator.free_block(low).unwrap();
+        assert_eq!(allocator.free_block(high), Err(AllocError::UnknownAddress(high)));
+        assert_eq!(allocator.free_block(low), Err(AllocError::DoubleFree(low)));
+        assert_eq!(allocator.freed_starts.iter().count(), 1);
+
+        // Taken into the block below when that grows, it is part of a live block again
+        assert_eq!(allocator.allocate(page()), Ok(low));
+        assert_eq!(allocator.allocate(page()), Ok(high));
+        allocator.free_block(high).unwrap();
+        let grown = allocator.reallocate(low, page(), 2 * PAGE_SIZE, |_, _, _| panic!("moved")).unwrap();
+        assert_eq!(grown, low);
+        assert_eq!(allocator.free_block(high), Err(AllocError::UnknownAddress(high)));
+        assert_eq!(allocator.freed_starts.iter().count(), 0);
+        allocator.free_block(low).unwrap();
+    }
+
+    #[test]
+    fn shared_block_is_freed_with_its_last_reference() {
+        let mut allocator = Allocator::new();
+        let start = allocator.allocate(page()).unwrap();
+        assert_eq!(allocator.share_block(start), Ok(2));
+
+        let mut copied = None;
+        let copy = allocator.make_unique(start, |from, to, len| copied = Some((from, to, len))).unwrap();
+        assert_ne!(copy, start);
+        assert_eq!(copied, Some((start, copy, PAGE_SIZE)));
+        assert_eq!(allocator.ref_count(start), Some(1));
+        assert_eq!(allocator.ref_count(copy), Some(1));
+        // Nobody else holds the copy, so writing to it needs no second copy
+        assert_eq!(allocator.make_unique(copy, |_, _, _| panic!("copied an unshared block")), Ok(copy));
+
+        allocator.free_block(start).unwrap();
+        assert_eq!(allocator.ref_count(start), None);
+        assert_eq!(allocator.free_block(start), Err(AllocError::DoubleFree(start)));
+        allocator.free_block(copy).unwrap();
+        assert_eq!(allocator.used_bytes(), 0);
+    }
+
+    #[test]
+    fn tagged_blocks_are_reported_and_held_to_quotas() {
+        let mut allocator = Allocator::new();
+        let (fs, shell) = (OwnerTag::Subsystem(3), OwnerTag::Process(1));
+        allocator.set_quota(fs, Some(3 * PAGE_SIZE));
+
+        let (first, _) = allocator.allocate_block_tagged(2 * PAGE_SIZE, fs).unwrap();
+        assert_eq!(
+            allocator.allocate_block_tagged(2 * PAGE_SIZE, fs),
+            Err(AllocError::QuotaExceeded {
+                tag: fs,
+                used: 2 * PAGE_SIZE,
+                limit: 3 * PAGE_SIZE,
+                requested: 2 * PAGE_SIZE
+            })
+        );
+        assert_eq!(allocator.allocate_block_tagged(usize::MAX, shell), Err(AllocError::TooLarge(usize::MAX)));
+        allocator.allocate_block_tagged(PAGE_SIZE, fs).unwrap();
+        allocator.allocate_block_tagged(5 * PAGE_SIZE, shell).unwrap();
+        allocator.allocate(page()).unwrap();
+
+        let owners: Vec<_> = allocator.leak_report().entries.iter().map(|entry| (entry.owner, entry.bytes)).collect();
+        assert_eq!(owners, [(Some(shell), 5 * PAGE_SIZE), (Some(fs), 3 * PAGE_SIZE), (None, PAGE_SIZE)]);
+
+        allocator.free_block(first).unwrap();
+        assert_eq!(allocator.owner_bytes(fs), PAGE_SIZE);
+        allocator.set_quota(fs, None);
+        allocator.allocate_block_tagged(4 * PAGE_SIZE, fs).unwrap();
+    }
+
+    #[test]
+    fn compaction_moves_only_unshared_movable_blocks_and_keeps_their_alignment() {
+        let config = AllocatorConfig::default()
+            .region(USER_MEM_START, 32 * PAGE_SIZE)
+            .block_exps(12, 17)
+            .layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::with_config(config).unwrap().with_policy(Box::new(FirstFit));
+        let at = |page: usize| USER_MEM_START + page * PAGE_SIZE;
+        let (movable, pinned) = (OwnerTag::Subsystem(1), OwnerTag::Process(9));
+        let pages = |count| Layout::from_size_align(count * PAGE_SIZE, 1).unwrap();
+
+        // Untagged pages 0 and 1, then a two page filler freed again below
+        let untagged = [allocator.allocate(page()).unwrap(), allocator.allocate(page()).unwrap()];
+        let filler = allocator.allocate(pages(2)).unwrap();
+        let large = allocator.allocate_tagged(pages(3), movable).unwrap();
+        let shared = allocator.allocate_tagged(page(), movable).unwrap();
+        allocator.share_block(shared).unwrap();
+        let other = allocator.allocate_tagged(page(), pinned).unwrap();
+        let page_on_two = Layout::from_size_align(PAGE_SIZE, 2 * PAGE_SIZE).unwrap();
+        let aligned = allocator.allocate_tagged(page_on_two, movable).unwrap();
+        assert_eq!([filler, large, shared, other, aligned], [at(2), at(4), at(7), at(8), at(10)]);
+        allocator.free_block(filler).unwrap();
+        let (used, owned) = (allocator.used_bytes(), allocator.owner_bytes(movable));
+
+        let mut moves = Vec::new();
+        let report = allocator.compact(&[movable], |from, to, len| moves.push((from, to, len)));
+        // The large block slides two pages down, over the first of its own. The aligned
+        // one would fit right after it but has to skip a page to stay aligned.
+        assert_eq!(moves, [(at(4), at(2), 3 * PAGE_SIZE), (at(10), at(6), PAGE_SIZE)]);
+        assert_eq!((report.moved_blocks, report.moved_bytes), (2, 4 * PAGE_SIZE));
+        assert_eq!(allocator.ref_count(shared), Some(2));
+        assert_eq!(allocator.ref_count(other), Some(1));
+        assert!(untagged.iter().all(|&start| allocator.ref_count(start) == Some(1)));
+        assert_eq!((allocator.used_bytes(), allocator.owner_bytes(movable)), (used, owned));
+        allocator.check_free_lists().unwrap();
+
+        // The old start of the aligned block was freed by the move; that of the large
+        // block is inside the block's new range
+        assert_eq!(allocator.free_block(aligned), Err(AllocError::DoubleFree(aligned)));
+        assert_eq!(allocator.free_block(large), Err(AllocError::UnknownAddress(large)));
+        allocator.free_block(at(2)).unwrap();
+        allocator.free_block(at(6)).unwrap();
+    }
+
+    #[test]
+    fn whole_region_stays_accounted_for_under_churn() {
+        let layouts = [RegionLayout::Random, RegionLayout::LargestAligned];
+        for (seed, layout) in layouts.iter().cycle().take(6).enumerate() {
+            let config = AllocatorConfig::default().seed(seed as u64).layout(*layout);
+            let mut allocator = Allocator::with_config(config).unwrap();
+            let mut workload = Workload::new(seed as u64 + 1).mix(4, 0).max_size(1 << 18).max_align_exp(15);
+            let mut live = Vec::new();
+
+            for round in 0..4000 {
+                match workload.next(live.len()) {
+                    Step::Free(index) => allocator.free_block(live.swap_remove(index)).unwrap(),
+                    Step::Allocate(layout) => {
+                        if let Ok(start) = allocator.allocate(layout) {
+                            live.push(start);
+                        }
+                    }
+                    Step::Resize(..) => unreachable!("the mix has no resizes"),
+                }
+
+                if round % 500 == 0 {
+                    assert_eq!(allocator.free_bytes() + allocator.used_bytes(), USER_MEM_SIZE);
+                    allocator.check_free_lists().unwrap();
+                    for (start, _) in allocator.freed_starts.iter() {
+                        assert!(allocator.free_addresses.search(start).is_some(), "0x{:x} still recorded", start);
+                    }
+                }
+            }
+
+            for start in live {
+                allocator.free_block(start).unwrap();
+            }
+            assert_eq!(allocator.free_bytes(), USER_MEM_SIZE);
+            assert_eq!(allocator.used_bytes(), 0);
+            allocator.check_free_lists().unwrap();
+        }
+    }
+
+    // Every block handed out, and every block still free, kept clear of the reserved ranges
+    fn assert_clear_of(allocator: &mut Allocator, reserved: &[(usize, usize)]) {
+        let mut live = Vec::new();
+        while let Ok(start) = allocator.allocate(page()) {
+            live.push(start);
+        }
+        for &start in &live {
+            for &(hole, end) in reserved {
+                assert!(start + PAGE_SIZE <= hole || start >= end, "0x{:x} is inside 0x{:x}-0x{:x}", start, hole, end);
+            }
+        }
+        allocator.check_reservations().unwrap();
+        for start in live {
+            allocator.free_block(start).unwrap();
+        }
+    }
+
+    #[test]
+    fn reserved_ranges_are_never_handed_out() {
+        let config = AllocatorConfig::default().region(USER_MEM_START, 64 * PAGE_SIZE);
+        let mut allocator = Allocator::with_config(config).unwrap();
+
+        // Half a page in and one page long touches two pages, both of which go
+        allocator.reserve(USER_MEM_START + 0x10800, PAGE_SIZE).unwrap();
+        assert_eq!(allocator.reserved_bytes(), 2 * PAGE_SIZE);
+        // Starting below the region only the part inside it is reserved
+        allocator.reserve(USER_MEM_START - 8 * PAGE_SIZE, 10 * PAGE_SIZE).unwrap();
+        assert_eq!(allocator.reserved_bytes(), 4 * PAGE_SIZE);
+        // Past the end of the region nothing is
+        allocator.reserve(USER_MEM_START + 64 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        assert_eq!(allocator.reserved_bytes(), 4 * PAGE_SIZE);
+        assert_eq!(
+            allocator.reserve(usize::MAX - PAGE_SIZE, 2 * PAGE_SIZE),
+            Err(AllocError::RangeOverflow { start: usize::MAX - PAGE_SIZE, len: 2 * PAGE_SIZE })
+        );
+
+        let reserved = [
+            (USER_MEM_START, USER_MEM_START + 2 * PAGE_SIZE),
+            (USER_MEM_START + 0x10000, USER_MEM_START + 0x12000),
+        ];
+        assert_clear_of(&mut allocator, &reserved);
+        assert_eq!(allocator.free_bytes(), 60 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn ranges_reserved_at_build_time_are_never_handed_out() {
+        let available = [(USER_MEM_START + 0x800, 32 * PAGE_SIZE), (USER_MEM_START + 40 * PAGE_SIZE, 8 * PAGE_SIZE)];
+        let reserved = [(USER_MEM_START + 4 * PAGE_SIZE + 1, 10)];
+        let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::from_ranges(&available, &reserved, config).unwrap();
+
+        // The partial first and last pages of the first range can't be used either
+        let holes = [
+            (0, USER_MEM_START + PAGE_SIZE),
+            (USER_MEM_START + 4 * PAGE_SIZE, USER_MEM_START + 5 * PAGE_SIZE),
+            (USER_MEM_START + 32 * PAGE_SIZE, USER_MEM_START + 40 * PAGE_SIZE),
+        ];
+        assert_clear_of(&mut allocator, &holes);
+        assert_eq!(allocator.free_bytes(), 38 * PAGE_SIZE);
+
+        assert_eq!(
+            Allocator::from_ranges(&available, &[(usize::MAX, 1)], AllocatorConfig::default()).err(),
+            Some(AllocError::RangeOverflow { start: usize::MAX, len: 1 })
+        );
+        let config = AllocatorConfig::default().block_exps(usize::BITS, usize::BITS + 1);
+        assert_eq!(
+            Allocator::from_ranges(&available, &[], config).err(),
+            Some(AllocError::InvalidConfig("block exponents out of range"))
+        );
+    }
+
+    // Keys 0, 10, .. 1990 inserted in ascending order, which rotates at every level on
+    // the way in, with every third one removed again, which rotates on the way out
+    fn ordered_tree() -> (AVLTree<usize, usize>, std::collections::BTreeMap<usize, usize>) {
+        let mut tree = AVLTree::new();
+        let mut expected = std::collections::BTreeMap::new();
+        for key in (0..200).map(|i| i * 10) {
+            tree.insert(key, key / 10);
+            expected.insert(key, key / 10);
+        }
+        for key in (0..200).step_by(3).map(|i| i * 10) {
+            assert_eq!(tree.remove(&key), expected.remove(&key));
+        }
+        (tree, expected)
+    }
+
+    #[test]
+    fn tree_walks_in_both_directions() {
+        let (mut tree, expected) = ordered_tree();
+        // 133 keys, so a balanced tree is at most 1.44 log2(135) deep
+        assert!(tree.depth() <= 10, "depth {}", tree.depth());
+
+        assert!(tree.iter().eq(expected.iter()));
+        assert!(tree.iter_rev().eq(expected.iter().rev()));
+        for (key, value) in tree.iter_mut() {
+            *value += key;
+        }
+        let mut descending = Vec::new();
+        for (key, value) in tree.iter_mut_rev() {
+            descending.push((*key, *value));
+            *value -= key;
+        }
+        let doubled: Vec<_> = expected.iter().rev().map(|(key, value)| (*key, value + key)).collect();
+        assert_eq!(descending, doubled);
+        assert!(tree.iter().eq(expected.iter()));
+
+        assert_eq!(tree.first(), expected.iter().next());
+        assert_eq!(tree.last(), expected.iter().next_back());
+        let empty: AVLTree<usize, usize> = AVLTree::new();
+        assert_eq!((empty.first(), empty.last(), empty.iter_rev().next()), (None, None, None));
+    }
+
+    #[test]
+    fn tree_lookups_agree_with_btreemap() {
+        let (tree, expected) = ordered_tree();
+        for probe in (0..2100).step_by(7) {
+            assert_eq!(tree.floor(&probe), expected.range(..=probe).next_back(), "floor of {}", probe);
+            assert_eq!(tree.ceiling(&probe), expected.range(probe..).next(), "ceiling of {}", probe);
+        }
+        assert!(tree.range(305..905).eq(expected.range(305..905)));
+        assert!(tree.range(300..=900).eq(expected.range(300..=900)));
+        assert!(tree.range(..45).eq(expected.range(..45)));
+        assert!(tree.range(1985..).eq(expected.range(1985..)));
+        assert_eq!(tree.range(2000..).next(), None);
+    }
+
+    #[test]
+    fn entries_fill_modify_and_leave_the_tree_whole() {
+        let (mut tree, mut expected) = ordered_tree();
+        // Ascending inserts rotate at nearly every level on the way back up
+        for key in (2000..2400).step_by(5) {
+            *tree.entry(key).or_insert(0) += key;
+            *expected.entry(key).or_insert(0) += key;
+        }
+        for key in (0..2400).step_by(15) {
+            tree.entry(key).and_modify(|value| *value *= 2).or_insert_with(|| 1);
+            expected.entry(key).and_modify(|value| *value *= 2).or_insert_with(|| 1);
+        }
+        // An entry left vacant hands back every node it walked past
+        for key in [1, 1001, 2401] {
+            assert!(matches!(tree.entry(key), Entry::Vacant(_)));
+        }
+        assert!(tree.iter().eq(expected.iter()));
+        assert!(tree.depth() <= 12, "depth {}", tree.depth());
+    }
+
+    // Measures a value as itself
+    struct Identity;
+
+    impl Augment<usize> for Identity {
+        fn measure(value: &usize) -> usize {
+            *value
+        }
+    }
+
+    // Checks every node's height and subtree_max against its children, returning both
+    fn assert_augmented(node: &Link<usize, usize>) -> (i32, usize) {
+        let Some(node) = node else { return (-1, 0) };
+        let (left_height, left_max) = assert_augmented(&node.left);
+        let (right_height, right_max) = assert_augmented(&node.right);
+        assert!((left_height - right_height).abs() <= 1, "unbalanced at {}", node.key);
+        assert_eq!(node.height, left_height.max(right_height) + 1, "height at {}", node.key);
+        assert_eq!(node.subtree_max, node.value.max(left_max).max(right_max), "subtree_max at {}", node.key);
+        (node.height, node.subtree_max)
+    }
+
+    #[test]
+    fn subtree_max_survives_rotations() {
+        let mut tree = AVLTree::<usize, usize, Identity>::new();
+        let mut expected = std::collections::BTreeMap::new();
+        let mut rng = XorShift64::new(7);
+        // Ascending keys rotate on insert; removing from the front rotates on the way out
+        for key in 0..500 {
+            let value = rng.next_u64() as usize % 1000;
+            tree.insert(key, value);
+            expected.insert(key, value);
+            assert_augmented(&tree.root);
+        }
+        for key in (0..500).filter(|key| key % 4 != 3) {
+            assert_eq!(tree.remove(&key), expected.remove(&key));
+            assert_augmented(&tree.root);
+        }
+
+        for size in (0..1100).step_by(50) {
+            let lowest = expected.iter().find(|(_, &value)| value >= size);
+            assert_eq!(tree.first_fit(size), lowest, "first fit of {}", size);
+            let from = 250;
+            let lowest = expected.range(from..).find(|(_, &value)| value >= size);
+            assert_eq!(tree.first_fit_from(&from, size), lowest, "first fit of {} from {}", size, from);
         }
     }
 }
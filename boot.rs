@@ -1,16 +1,23 @@
 #![feature(alloc_error_handler)]
-#![feature(asm)]
 #![feature(lang_items)]
 #![no_std]
 
-extern crate alloc;
 extern crate rlibc;
 
-use alloc::alloc::{alloc_zeroed, Layout};
+// The kernel is built from the same modules as the host binary, with this file as the
+// crate root in place of allocator.rs. That brings in extern crate alloc as well.
+include!("allocator.rs");
+
+use core::arch::asm;
 use core::ptr::null_mut;
-use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{self, AtomicBool, AtomicUsize};
+
+use crate::locked::{LockedAllocator, MetadataPool, SpinLock};
+use crate::slab::{size_class, SlabAllocator, SIZE_CLASSES};
 
-// Bootloader function
+// Bootloader function. Nothing calls it, so its asm is emitted once and the named
+// labels can't collide.
+#[allow(named_asm_labels)]
 fn boot_loader() {
     unsafe {
         asm!(
@@ -45,8 +52,8 @@ fn boot_loader() {
             "jmp CODE64_INIT",     // Jump to the 64-bit code
 
             // 64-bit initialization code
-            BITS 64
-            CODE64_INIT:
+            "bits 64",
+            "CODE64_INIT:",
                 "mov ax, 0x10", // Set up the data segment
                 "mov ds, ax",
                 "mov es, ax",
@@ -68,27 +75,179 @@ fn boot_loader() {
     }
 }
 
-// Global allocator
+// Global allocator. It has no region until rust_munch calls init; until then every
+// allocation returns null.
 #[global_allocator]
-static ALLOCATOR: Allocator = Allocator::new();
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    heap: LockedAllocator::new(),
+    slabs: SpinLock::new(None),
+    metadata: MetadataPool::new(),
+    metadata_pages: LockedAllocator::new(),
+    metadata_range: (AtomicUsize::new(0), AtomicUsize::new(0)),
+    in_heap: [const { AtomicBool::new(false) }; MAX_CPUS],
+    in_metadata_pages: [const { AtomicBool::new(false) }; MAX_CPUS],
+};
+
+const METADATA_POOL_SIZE: usize = 4 << 20;
+// Taken off the top of the heap's region for metadata_pages
+const METADATA_PAGES_SIZE: usize = 16 << 20;
+// APIC ids from cpuid are 8 bits
+const MAX_CPUS: usize = 256;
+
+// Objects of the slab size classes are packed into slabs cut from heap pages; larger
+// ones are whole blocks of the heap.
+//
+// The heap's own trees allocate and free as they change, from inside the heap's lock.
+// Those calls come back in here on the same CPU and are served from the metadata pool
+// instead of deadlocking on the lock. Whether a CPU is inside the heap is tracked for
+// each CPU, so another CPU holding the lock only means waiting for it. Bookkeeping too
+// large for the pool, such as the page list of a block of more than 8192 pages, comes
+// from metadata_pages, a page allocator of its own over a range the heap never sees.
+struct KernelAllocator {
+    heap: LockedAllocator,
+    // Taken before the heap's lock whenever both are held
+    slabs: SpinLock<Option<SlabAllocator>>,
+    metadata: MetadataPool<METADATA_POOL_SIZE>,
+    metadata_pages: LockedAllocator,
+    // Start and end of metadata_pages' region, so a free can tell its blocks apart
+    // without taking its lock
+    metadata_range: (AtomicUsize, AtomicUsize),
+    // Set while the CPU with that APIC id runs heap code. Heap code runs with the lock
+    // held, so it is never moved to another CPU halfway.
+    in_heap: [AtomicBool; MAX_CPUS],
+    // The same for metadata_pages, whose trees fall back on the metadata pool alone
+    in_metadata_pages: [AtomicBool; MAX_CPUS],
+}
+
+// Initial APIC id of the running CPU
+fn cpu_id() -> usize {
+    (core::arch::x86_64::__cpuid(1).ebx >> 24) as usize
+}
+
+// Runs f with this CPU's flag in flags set. None if it is set already: an allocator
+// allocating for its own trees, or an interrupt that came in while it was.
+fn enter<R>(flags: &[AtomicBool; MAX_CPUS], f: impl FnOnce() -> R) -> Option<R> {
+    let flag = &flags[cpu_id()];
+    if flag.swap(true, atomic::Ordering::Acquire) {
+        return None;
+    }
+    let result = f();
+    flag.store(false, atomic::Ordering::Release);
+    Some(result)
+}
+
+// Whether the slabs serve layout rather than passing it on to the heap
+fn in_slab(layout: Layout) -> bool {
+    size_class(layout, SIZE_CLASSES[0], SIZE_CLASSES.len()).is_some()
+}
+
+impl KernelAllocator {
+    // The top METADATA_PAGES_SIZE bytes of config's region go to metadata_pages and
+    // the rest to the heap
+    fn init(&self, config: AllocatorConfig) -> Result<(), AllocError> {
+        let heap_size = config
+            .region_size
+            .checked_sub(METADATA_PAGES_SIZE)
+            .filter(|&size| size > 0)
+            .ok_or(AllocError::InvalidConfig("region leaves no room for the heap"))?;
+        let heap_start = config.region_start;
+        let metadata_start = heap_start + heap_size;
+        let metadata = config.clone().region(metadata_start, METADATA_PAGES_SIZE);
+        let heap = config.region(heap_start, heap_size);
+
+        enter(&self.in_heap, || {
+            self.metadata_pages.init(metadata)?;
+            self.heap.init(heap)?;
+            *self.slabs.lock() = Some(SlabAllocator::new());
+            self.metadata_range.0.store(metadata_start, atomic::Ordering::Release);
+            self.metadata_range.1.store(metadata_start + METADATA_PAGES_SIZE, atomic::Ordering::Release);
+            Ok(())
+        })
+        .unwrap_or(Err(AllocError::InvalidConfig("allocator initialized from inside itself")))
+    }
+
+    fn allocate(&self, layout: Layout) -> Result<usize, AllocError> {
+        let mut slabs = self.slabs.lock();
+        let slabs = slabs.as_mut().ok_or(AllocError::Uninitialized)?;
+        self.heap.with(|pages| slabs.allocate(pages, layout))
+    }
+
+    fn free(&self, start: usize) -> Result<(), AllocError> {
+        let mut slabs = self.slabs.lock();
+        let slabs = slabs.as_mut().ok_or(AllocError::Uninitialized)?;
+        self.heap.with(|pages| slabs.free(pages, start))
+    }
+
+    // For the heap's trees, from inside the heap
+    fn allocate_metadata(&self, layout: Layout) -> Result<usize, AllocError> {
+        self.metadata.allocate(layout).or_else(|err| {
+            enter(&self.in_metadata_pages, || self.metadata_pages.allocate(layout)).unwrap_or(Err(err))
+        })
+    }
 
-// Implement GlobalAlloc for Allocator
-unsafe impl alloc::alloc::GlobalAlloc for Allocator {
+    fn in_metadata_pages(&self, address: usize) -> bool {
+        let (start, end) = &self.metadata_range;
+        address >= start.load(atomic::Ordering::Acquire) && address < end.load(atomic::Ordering::Acquire)
+    }
+}
+
+unsafe impl alloc::alloc::GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // Modify the allocation code to use the Allocator
-        // ...
+        let result = match enter(&self.in_heap, || self.allocate(layout)) {
+            Some(result) => result,
+            None => self.allocate_metadata(layout),
+        };
+        result.map_or(null_mut(), |start| start as *mut u8)
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // Modify the deallocation code to use the Allocator
-        // ...
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // dealloc has no way to report a bad pointer; the debug allocator is the place
+        // to look for those
+        if self.metadata.contains(ptr as usize) {
+            let _ = self.metadata.free(ptr as usize, layout);
+            return;
+        }
+        // Blocks are leaked the same way as below if metadata_pages is busy on this CPU
+        if self.in_metadata_pages(ptr as usize) {
+            let _ = enter(&self.in_metadata_pages, || self.metadata_pages.free(ptr as usize));
+            return;
+        }
+        // An interrupt handler freeing heap memory while its CPU is inside the heap
+        // can't take the lock without deadlocking, so that block is leaked
+        let _ = enter(&self.in_heap, || self.free(ptr as usize));
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        // Only whole heap blocks can be resized in place; anything else is copied
+        let in_place = !self.metadata.contains(ptr as usize)
+            && !self.in_metadata_pages(ptr as usize)
+            && !in_slab(layout)
+            && !in_slab(new_layout);
+        if !in_place {
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            return new_ptr;
+        }
+        // Grows and shrinks in place when it can and only copies when the block moves
+        let copy = |from: usize, to: usize, len: usize| {
+            core::ptr::copy_nonoverlapping(from as *const u8, to as *mut u8, len)
+        };
+        match enter(&self.in_heap, || self.heap.reallocate(ptr as usize, layout, new_size, copy)) {
+            Some(Ok(start)) => start as *mut u8,
+            _ => null_mut(),
+        }
+    }
+}
+
     // Constants
     const KERNEL_OFFSET: usize = 0xffffffff80000000;
     const PHYSICAL_OFFSET: usize = 0xffff_8000_0000_0000;
     const PT_ENTRIES: usize = 512;
     const PT_LEVELS: usize = 4;
-    const PAGE_SIZE: usize = 4096;
     const KERNEL_BASE: usize = 0xffffffff_80000000;
     const KERNEL_SIZE: usize = 1 << 30; // 1GB
     const PAGE_FLAGS: u64 = 0x1 | 0x2 | 0x40; // Present, Writable, NX (no execute)
@@ -141,13 +300,13 @@ impl PageTable {
     fn get_next_level(&self, virtual_address: usize, level: usize) -> *const PageTable {
         let entry = self.get_entry(virtual_address, level);
         let table_address = entry.frame_address();
-        (table_address as *const PageTable)
+        table_address as *const PageTable
     }
 
     fn get_next_level_mut(&mut self, virtual_address: usize, level: usize) -> *mut PageTable {
         let entry = self.get_entry_mut(virtual_address, level);
         let table_address = entry.frame_address();
-        (table_address as *mut PageTable)
+        table_address as *mut PageTable
     }
 
     fn map_page(&mut self, virtual_address: usize, physical_address: usize, flags: u64) {
@@ -176,14 +335,14 @@ impl PageTable {
 // rust_munch function
 #[no_mangle]
 pub extern "C" fn rust_munch() -> ! {
-unsafe {
-        let mut cpuid_info: [u32; 4] = [0; 4];
-        asm!("cpuid" : "={eax}"(cpuid_info[0]),
-                       "={ebx}"(cpuid_info[1]), 
-                       "={ecx}"(cpuid_info[2]), 
-                       "={edx}"(cpuid_info[3]) : 
-                       "{eax}"(0) : : "volatile");
+    // The page tables below are the first thing allocated, so the heap needs its region
+    // before them. The seed keeps the block layout the same on every boot.
+    let heap = AllocatorConfig::default().layout(RegionLayout::LargestAligned).seed(0);
+    if ALLOCATOR.init(heap).is_err() {
+        loop {}
+    }
 
+unsafe {
                        let mut kern_mem = PageTable::new();
 
                        // Map kernel memory
@@ -194,12 +353,12 @@ unsafe {
                         }
 
          // Enable paging by setting the Paging Flag (PG) in the control register CR0
-    asm!("mov %cr0, %rax ; or $$0x80000000, %rax ; mov %rax, %cr0");
+    asm!("mov {0}, cr0", "or {0}, 0x80000000", "mov cr0, {0}", out(reg) _);
 
     let mut cr4: usize;
-    asm!("mov %cr4, $0" : "=r"(cr4) :: "memory" : "volatile");
+    asm!("mov {}, cr4", out(reg) cr4);
     cr4 |= 1 << 5; // Enable global pages
-    asm!("mov $0, %cr4" :: "r"(cr4) : "memory" : "volatile");
+    asm!("mov cr4, {}", in(reg) cr4);
 
     // Jump to the kernel entry point
     let kernel_entry: extern "C" fn() -> ! = core::mem::transmute(KERNEL_BASE);
@@ -208,7 +367,6 @@ unsafe {
 }
 
 #[lang = "eh_personality"]
-#[no_mangle]
 pub extern "C" fn rust_eh_personality() {}
 
 // Panic handler
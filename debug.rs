@@ -0,0 +1,427 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt;
+
+use crate::{AVLTree, AllocError, Allocator, PAGE_SIZE};
+
+// What DebugAllocator needs from the memory behind the region. The allocator itself
+// only tracks addresses, so the bytes are reached through this instead.
+pub trait DebugMemory {
+    fn fill(&mut self, start: usize, len: usize, byte: u8);
+
+    // First address in [start, start + len) that does not hold byte
+    fn find_mismatch(&self, start: usize, len: usize, byte: u8) -> Option<usize>;
+
+    // Makes whole pages of padding read only while their block is live, so a write into
+    // one faults there and then instead of being found on free. Memory that can't change
+    // its mappings leaves guard pages to be checked as canaries, like the rest of the
+    // padding.
+    fn protect(&mut self, _start: usize, _len: usize, _protected: bool) {}
+}
+
+// The region as it is mapped in the kernel, where addresses are usable as pointers.
+// Tests use it over a buffer of their own.
+#[cfg(any(test, target_os = "none"))]
+#[derive(Debug, Default)]
+pub struct RawMemory;
+
+#[cfg(any(test, target_os = "none"))]
+impl DebugMemory for RawMemory {
+    fn fill(&mut self, start: usize, len: usize, byte: u8) {
+        unsafe { core::ptr::write_bytes(start as *mut u8, byte, len) }
+    }
+
+    fn find_mismatch(&self, start: usize, len: usize, byte: u8) -> Option<usize> {
+        let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, len) };
+        bytes.iter().position(|&b| b != byte).map(|offset| start + offset)
+    }
+}
+
+// Stand-in for the region on the host. Pages are created on first write and read as
+// zero until then. A write to a protected page panics, as the fault would end the
+// program in the kernel.
+#[derive(Debug)]
+pub struct HostMemory {
+    pages: AVLTree<usize, Vec<u8>>,
+    // Starts of the pages protect has made read only
+    protected: AVLTree<usize, ()>,
+}
+
+impl HostMemory {
+    pub fn new() -> Self {
+        HostMemory {
+            pages: AVLTree::new(),
+            protected: AVLTree::new(),
+        }
+    }
+
+    pub fn write(&mut self, start: usize, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            let address = start + offset;
+            self.page_mut(address)[address % PAGE_SIZE] = byte;
+        }
+    }
+
+    // The page holding address, to be written to
+    fn page_mut(&mut self, address: usize) -> &mut Vec<u8> {
+        let page_start = address - address % PAGE_SIZE;
+        if self.protected.search(&page_start).is_some() {
+            panic!("write to 0x{:x} in a guard page", address);
+        }
+        self.pages.entry(page_start).or_insert_with(|| vec![0; PAGE_SIZE])
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        self.pages
+            .search(&(address - address % PAGE_SIZE))
+            .map_or(0, |page| page[address % PAGE_SIZE])
+    }
+}
+
+impl DebugMemory for HostMemory {
+    fn fill(&mut self, start: usize, len: usize, byte: u8) {
+        let mut address = start;
+        while address < start + len {
+            let page_start = address - address % PAGE_SIZE;
+            let end = (page_start + PAGE_SIZE).min(start + len);
+            self.page_mut(address)[address - page_start..end - page_start].fill(byte);
+            address = end;
+        }
+    }
+
+    fn find_mismatch(&self, start: usize, len: usize, byte: u8) -> Option<usize> {
+        (start..start + len).find(|&address| self.read(address) != byte)
+    }
+
+    // Guard pages are still read, so their canaries can be checked
+    fn protect(&mut self, start: usize, len: usize, protected: bool) {
+        for page in (start..start + len).step_by(PAGE_SIZE) {
+            if protected {
+                self.protected.insert(page, ());
+            } else {
+                self.protected.remove(&page);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DebugConfig {
+    // Minimum padding on each side of an allocation. A multiple of PAGE_SIZE gives
+    // whole guard pages on both sides.
+    pub guard_bytes: usize,
+    pub canary: u8,
+    pub poison: u8,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        DebugConfig {
+            guard_bytes: 16,
+            canary: 0xca,
+            poison: 0xdd,
+        }
+    }
+}
+
+// Every report names the block involved by its first and last byte, padding included
+#[derive(Debug, PartialEq, Eq)]
+pub enum DebugError {
+    Alloc(AllocError),
+    // The padding around an allocation no longer holds the canary
+    CanaryOverwritten { address: usize, start: usize, end: usize },
+    // A freed block was written to before it was handed out again
+    UseAfterFree { address: usize, start: usize, end: usize },
+    DoubleFree { address: usize, start: usize, end: usize },
+    // Neither live nor recently freed; never handed out by this allocator
+    UnknownFree(usize),
+}
+
+impl From<AllocError> for DebugError {
+    fn from(err: AllocError) -> Self {
+        DebugError::Alloc(err)
+    }
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::Alloc(err) => write!(f, "{}", err),
+            DebugError::CanaryOverwritten { address, start, end } => write!(
+                f,
+                "canary at 0x{:x} overwritten in block 0x{:x}-0x{:x}",
+                address, start, end
+            ),
+            DebugError::UseAfterFree { address, start, end } => write!(
+                f,
+                "write to 0x{:x} after block 0x{:x}-0x{:x} was freed",
+                address, start, end
+            ),
+            DebugError::DoubleFree { address, start, end } => write!(
+                f,
+                "double free of 0x{:x} in block 0x{:x}-0x{:x}",
+                address, start, end
+            ),
+            DebugError::UnknownFree(address) => write!(f, "free of 0x{:x}, which was never allocated", address),
+        }
+    }
+}
+
+// A block handed out through DebugAllocator: the caller sees address, and the block
+// runs from start for size bytes with canaries on either side of the caller's bytes
+#[derive(Debug, Clone, Copy)]
+struct DebugBlock {
+    address: usize,
+    start: usize,
+    size: usize,
+    layout: Layout,
+}
+
+impl DebugBlock {
+    fn end(&self) -> usize {
+        self.start + self.size - 1
+    }
+
+    fn front(&self) -> (usize, usize) {
+        (self.start, self.address - self.start)
+    }
+
+    fn back(&self) -> (usize, usize) {
+        let after = self.address + self.layout.size();
+        (after, self.start + self.size - after)
+    }
+}
+
+// Checking wrapper around Allocator. Allocations are padded with canaries that are
+// checked on free, freed blocks are poisoned and checked again before they are reused,
+// and recently freed blocks are remembered so a second free is reported as one.
+#[derive(Debug)]
+pub struct DebugAllocator<M: DebugMemory> {
+    allocator: Allocator,
+    memory: M,
+    config: DebugConfig,
+    // Live blocks by the address the caller holds
+    live: AVLTree<usize, DebugBlock>,
+    // Poisoned blocks by start, dropped once any of their memory is handed out again
+    freed: AVLTree<usize, DebugBlock>,
+}
+
+impl<M: DebugMemory> DebugAllocator<M> {
+    pub fn new(allocator: Allocator, memory: M, config: DebugConfig) -> Self {
+        DebugAllocator {
+            allocator,
+            memory,
+            config,
+            live: AVLTree::new(),
+            freed: AVLTree::new(),
+        }
+    }
+
+    pub fn allocator(&self) -> &Allocator {
+        &self.allocator
+    }
+
+    pub fn into_inner(self) -> Allocator {
+        self.allocator
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> Result<usize, DebugError> {
+        let guard = self.config.guard_bytes;
+        let front = (guard + layout.align() - 1) & !(layout.align() - 1);
+        let padded = Layout::from_size_align(front + layout.size() + guard, layout.align())
+            .map_err(|_| AllocError::InvalidLayout(layout))?;
+        let start = self.allocator.allocate(padded)?;
+        let block = DebugBlock {
+            address: start + front,
+            start,
+            size: self.allocator.rounded_size(padded.size()),
+            layout,
+        };
+
+        if let Err(err) = self.check_reused(block) {
+            self.allocator.free_block(start)?;
+            return Err(err);
+        }
+
+        let (front_start, front_len) = block.front();
+        let (back_start, back_len) = block.back();
+        self.memory.fill(front_start, front_len, self.config.canary);
+        self.memory.fill(back_start, back_len, self.config.canary);
+        if guard >= PAGE_SIZE {
+            self.protect_guards(block, true);
+        }
+
+        self.live.insert(block.address, block);
+        Ok(block.address)
+    }
+
+    pub fn free(&mut self, address: usize) -> Result<(), DebugError> {
+        let block = match self.live.remove(&address) {
+            Some(block) => block,
+            None => {
+                return Err(match self.freed.floor(&address) {
+                    Some((_, block)) if block.address == address => DebugError::DoubleFree {
+                        address,
+                        start: block.start,
+                        end: block.end(),
+                    },
+                    _ => DebugError::UnknownFree(address),
+                })
+            }
+        };
+
+        if self.config.guard_bytes >= PAGE_SIZE {
+            self.protect_guards(block, false);
+        }
+        let overwritten = self.check_canaries(&block);
+
+        // The block goes back even if its canaries are gone, so it isn't lost as well
+        self.memory.fill(block.start, block.size, self.config.poison);
+        self.allocator.free_block(block.start)?;
+        self.freed.insert(block.start, block);
+        overwritten
+    }
+
+    // Checks the canaries of every live block
+    pub fn check(&self) -> Result<(), DebugError> {
+        self.live.iter().try_for_each(|(_, block)| self.check_canaries(block))
+    }
+
+    fn check_canaries(&self, block: &DebugBlock) -> Result<(), DebugError> {
+        let (front_start, front_len) = block.front();
+        let (back_start, back_len) = block.back();
+        let overwritten = self
+            .memory
+            .find_mismatch(front_start, front_len, self.config.canary)
+            .or_else(|| self.memory.find_mismatch(back_start, back_len, self.config.canary));
+
+        match overwritten {
+            Some(address) => Err(DebugError::CanaryOverwritten {
+                address,
+                start: block.start,
+                end: block.end(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Before any of a freed block is handed out again, makes sure it still holds
+    // nothing but poison, then forgets it
+    fn check_reused(&mut self, block: DebugBlock) -> Result<(), DebugError> {
+        let block_end = block.start + block.size;
+        let mut overlapping: Vec<usize> = self.freed.range(block.start..block_end).map(|(start, _)| *start).collect();
+        if let Some((start, freed)) = self.freed.floor(&block.start) {
+            if *start < block.start && start + freed.size > block.start {
+                overlapping.push(*start);
+            }
+        }
+
+        let mut result = Ok(());
+        for start in overlapping {
+            let freed = self.freed.remove(&start).unwrap();
+            if let Some(address) = self.memory.find_mismatch(freed.start, freed.size, self.config.poison) {
+                result = result.and(Err(DebugError::UseAfterFree {
+                    address,
+                    start: freed.start,
+                    end: freed.end(),
+                }));
+            }
+        }
+        result
+    }
+
+    // Whole pages of padding on either side of the caller's bytes
+    fn protect_guards(&mut self, block: DebugBlock, protected: bool) {
+        let (front_start, front_len) = block.front();
+        let front_pages = front_len - front_len % PAGE_SIZE;
+        let (back_start, back_len) = block.back();
+        let back_pages_start = (back_start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let back_end = back_start + back_len;
+
+        if front_pages > 0 {
+            self.memory.protect(front_start, front_pages, protected);
+        }
+        if back_end > back_pages_start {
+            self.memory.protect(back_pages_start, back_end - back_pages_start, protected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AllocatorConfig;
+    use std::alloc::{alloc_zeroed, dealloc};
+
+    const PAGES: usize = 16;
+
+    #[test]
+    fn raw_memory_catches_overflow_and_use_after_free() {
+        let buffer = Layout::from_size_align(PAGES * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let base = unsafe { alloc_zeroed(buffer) };
+        assert!(!base.is_null());
+
+        let config = AllocatorConfig::default().region(base as usize, PAGES * PAGE_SIZE);
+        let allocator = Allocator::with_config(config).unwrap();
+        let mut checked = DebugAllocator::new(allocator, RawMemory, DebugConfig::default());
+        let object = Layout::from_size_align(100, 8).unwrap();
+
+        let address = checked.allocate(object).unwrap();
+        unsafe { core::ptr::write_bytes(address as *mut u8, 0x55, object.size()) };
+        assert_eq!(checked.check(), Ok(()));
+        // One byte past the end
+        unsafe { *((address + object.size()) as *mut u8) = 0 };
+        assert!(matches!(checked.check(), Err(DebugError::CanaryOverwritten { .. })));
+        assert!(matches!(checked.free(address), Err(DebugError::CanaryOverwritten { .. })));
+        assert!(matches!(checked.free(address), Err(DebugError::DoubleFree { .. })));
+        assert_eq!(checked.allocator().used_bytes(), 0);
+
+        // Written to after the free, then handed out again
+        let stale = address;
+        let address = checked.allocate(object).unwrap();
+        checked.free(address).unwrap();
+        unsafe { *(stale as *mut u8) = 1 };
+        let reused = (0..PAGES).map(|_| checked.allocate(object)).find(|result| result.is_err());
+        assert!(matches!(reused, Some(Err(DebugError::UseAfterFree { .. }))));
+
+        drop(checked);
+        unsafe { dealloc(base, buffer) };
+    }
+
+    // Whole guard pages on either side, on the host
+    fn guarded() -> DebugAllocator<HostMemory> {
+        let config = DebugConfig {
+            guard_bytes: PAGE_SIZE,
+            ..DebugConfig::default()
+        };
+        DebugAllocator::new(Allocator::new(), HostMemory::new(), config)
+    }
+
+    #[test]
+    fn guard_pages_are_protected_only_while_their_block_is_live() {
+        let mut checked = guarded();
+        let object = Layout::from_size_align(100, 8).unwrap();
+        let address = checked.allocate(object).unwrap();
+        // A page in front, and the page after the one the object ends in
+        let guards = [address - PAGE_SIZE, address + PAGE_SIZE];
+        assert_eq!(checked.memory_mut().protected.iter().map(|(page, _)| *page).collect::<Vec<_>>(), guards);
+
+        // The partial page past the object is padding too, but only its canaries catch this
+        checked.memory_mut().write(address, &[0x55; 101]);
+        assert!(matches!(checked.free(address), Err(DebugError::CanaryOverwritten { .. })));
+        assert_eq!(checked.memory_mut().protected.iter().count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "in a guard page")]
+    fn writes_into_a_guard_page_fault() {
+        let mut checked = guarded();
+        let address = checked.allocate(Layout::from_size_align(100, 8).unwrap()).unwrap();
+        checked.memory_mut().write(address - 1, &[0]);
+    }
+}
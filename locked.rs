@@ -0,0 +1,374 @@
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{AllocError, Allocator, AllocatorConfig};
+#[cfg(any(test, target_os = "none"))]
+use crate::slab::size_class;
+#[cfg(not(target_os = "none"))]
+use crate::{RegionLayout, Step, Workload};
+
+// Test and test-and-set lock for no_std, where there is no Mutex to use
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(target_os = "none")]
+type Lock<T> = SpinLock<T>;
+#[cfg(not(target_os = "none"))]
+type Lock<T> = std::sync::Mutex<T>;
+
+// Allocator behind a lock, so it can live in a static and be shared between CPUs or
+// threads. It starts out empty and is given its region once that is known, by init at
+// boot; until then every allocation fails.
+pub struct LockedAllocator {
+    inner: Lock<Option<Allocator>>,
+}
+
+impl LockedAllocator {
+    pub const fn new() -> Self {
+        LockedAllocator { inner: Lock::new(None) }
+    }
+
+    pub fn init(&self, config: AllocatorConfig) -> Result<(), AllocError> {
+        let mut inner = self.lock();
+        if inner.is_some() {
+            return Err(AllocError::InvalidConfig("allocator is already initialized"));
+        }
+        *inner = Some(Allocator::with_config(config)?);
+        Ok(())
+    }
+
+    #[cfg(target_os = "none")]
+    fn lock(&self) -> impl DerefMut<Target = Option<Allocator>> + '_ {
+        self.inner.lock()
+    }
+
+    // A poisoned lock is taken anyway: the panic is reported by the thread that hit it,
+    // and refusing every later allocation would only add a second failure
+    #[cfg(not(target_os = "none"))]
+    fn lock(&self) -> impl DerefMut<Target = Option<Allocator>> + '_ {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Runs f on the allocator under the lock
+    pub fn with<R>(&self, f: impl FnOnce(&mut Allocator) -> Result<R, AllocError>) -> Result<R, AllocError> {
+        match self.lock().as_mut() {
+            Some(allocator) => f(allocator),
+            None => Err(AllocError::Uninitialized),
+        }
+    }
+
+    pub fn allocate(&self, layout: Layout) -> Result<usize, AllocError> {
+        self.with(|allocator| allocator.allocate(layout))
+    }
+
+    pub fn free(&self, start: usize) -> Result<(), AllocError> {
+        self.with(|allocator| allocator.free_block(start))
+    }
+
+    pub fn reallocate<F>(&self, start: usize, old: Layout, new_size: usize, copy: F) -> Result<usize, AllocError>
+    where
+        F: FnOnce(usize, usize, usize),
+    {
+        self.with(|allocator| allocator.reallocate(start, old, new_size, copy))
+    }
+}
+
+// Size classes of MetadataPool: powers of two from 16 bytes up to 64 KiB
+#[cfg(any(test, target_os = "none"))]
+const POOL_MIN_CLASS: usize = 16;
+#[cfg(any(test, target_os = "none"))]
+const POOL_CLASSES: usize = 13;
+
+// Memory for the heap's own bookkeeping while it is the global allocator, when its
+// trees allocate from inside the heap and can't be served by it. Objects are carved
+// from a fixed array by size class, and a freed object goes on its class's free list
+// for the next allocation of that class, so the trees churning through nodes keep
+// reusing the same memory instead of running the array dry.
+#[cfg(any(test, target_os = "none"))]
+#[repr(C, align(4096))]
+pub struct MetadataPool<const SIZE: usize> {
+    memory: UnsafeCell<[u8; SIZE]>,
+    state: SpinLock<PoolState>,
+}
+
+#[cfg(any(test, target_os = "none"))]
+struct PoolState {
+    // Offset of the first byte never handed out
+    frontier: usize,
+    // Address of the first free object of each class, 0 if there is none. A free
+    // object holds the address of the next one.
+    free: [usize; POOL_CLASSES],
+}
+
+#[cfg(any(test, target_os = "none"))]
+unsafe impl<const SIZE: usize> Sync for MetadataPool<SIZE> {}
+
+#[cfg(any(test, target_os = "none"))]
+impl<const SIZE: usize> MetadataPool<SIZE> {
+    pub const fn new() -> Self {
+        MetadataPool {
+            memory: UnsafeCell::new([0; SIZE]),
+            state: SpinLock::new(PoolState {
+                frontier: 0,
+                free: [0; POOL_CLASSES],
+            }),
+        }
+    }
+
+    fn base(&self) -> usize {
+        self.memory.get() as usize
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.base() && address < self.base() + SIZE
+    }
+
+    pub fn allocate(&self, layout: Layout) -> Result<usize, AllocError> {
+        let (class, size) = size_class(layout, POOL_MIN_CLASS, POOL_CLASSES).ok_or(AllocError::InvalidLayout(layout))?;
+        let mut state = self.state.lock();
+
+        let head = state.free[class];
+        if head != 0 {
+            state.free[class] = unsafe { *(head as *const usize) };
+            return Ok(head);
+        }
+        let start = state.frontier.next_multiple_of(size);
+        if start + size > SIZE {
+            return Err(AllocError::OutOfMemory(layout));
+        }
+        state.frontier = start + size;
+        Ok(self.base() + start)
+    }
+
+    // layout has to be the one the object was allocated with
+    pub fn free(&self, start: usize, layout: Layout) -> Result<(), AllocError> {
+        match size_class(layout, POOL_MIN_CLASS, POOL_CLASSES) {
+            Some((class, size)) if self.contains(start) && (start - self.base()).is_multiple_of(size) => {
+                let mut state = self.state.lock();
+                unsafe { *(start as *mut usize) = state.free[class] };
+                state.free[class] = start;
+                Ok(())
+            }
+            _ => Err(AllocError::UnknownAddress(start)),
+        }
+    }
+}
+
+// `allocator stress [threads]`: threads hammer one LockedAllocator with random
+// allocations, reallocations and frees. Every block a thread holds is stamped in a
+// shared table, so the same address handed to two holders at once is caught, and the
+// free lists are checked once everything is returned.
+#[cfg(not(target_os = "none"))]
+pub fn stress(threads: usize, operations: usize) -> Result<StressReport, String> {
+    let allocator = LockedAllocator::new();
+    allocator
+        .init(AllocatorConfig::default().layout(RegionLayout::LargestAligned).seed(1))
+        .map_err(|err| err.to_string())?;
+    // Start of every held block to its end and holder
+    let owners = std::sync::Mutex::new(std::collections::BTreeMap::new());
+    let claim = |start: usize, size: usize, thread: usize| {
+        let mut owners = owners.lock().unwrap();
+        if let Some((other, &(end, holder))) = owners.range(..start + size).next_back() {
+            if end > start {
+                return Err(format!(
+                    "0x{:x} handed to thread {} while thread {} holds 0x{:x}-0x{:x}",
+                    start, thread, holder, other, end - 1
+                ));
+            }
+        }
+        owners.insert(start, (start + size, thread));
+        Ok(())
+    };
+    let release = |start: usize| {
+        owners.lock().unwrap().remove(&start);
+    };
+
+    let failures = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|thread| {
+                let (allocator, claim, release) = (&allocator, &claim, &release);
+                scope.spawn(move || -> Result<usize, String> {
+                    let mut workload = Workload::new(thread as u64 + 1).mix(4, 4);
+                    let mut live: Vec<(usize, Layout)> = Vec::new();
+                    let mut failures = 0;
+
+                    for _ in 0..operations {
+                        match workload.next(live.len()) {
+                            Step::Free(index) => {
+                                let (start, _) = live.swap_remove(index);
+                                release(start);
+                                allocator.free(start).map_err(|err| err.to_string())?;
+                            }
+                            Step::Resize(index, size) => {
+                                let (start, layout) = live[index];
+                                // Unstamped first: in place, the block keeps its start
+                                release(start);
+                                match allocator.reallocate(start, layout, size, |_, _, _| {}) {
+                                    Ok(moved) => {
+                                        claim(moved, size, thread)?;
+                                        live[index] = (moved, Layout::from_size_align(size, 1).unwrap());
+                                    }
+                                    Err(_) => {
+                                        claim(start, layout.size(), thread)?;
+                                        failures += 1;
+                                    }
+                                }
+                            }
+                            Step::Allocate(layout) => match allocator.allocate(layout) {
+                                Ok(start) => {
+                                    claim(start, layout.size(), thread)?;
+                                    live.push((start, layout));
+                                }
+                                Err(_) => failures += 1,
+                            },
+                        }
+                    }
+
+                    for (start, _) in live {
+                        release(start);
+                        allocator.free(start).map_err(|err| err.to_string())?;
+                    }
+                    Ok(failures)
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().map_err(|_| "stress thread panicked".to_string())?)
+            .sum::<Result<usize, String>>()
+    })?;
+
+    allocator
+        .with(|allocator| {
+            allocator.check_free_lists()?;
+            Ok(StressReport {
+                threads,
+                operations,
+                failures,
+                free_bytes: allocator.free_bytes(),
+                used_bytes: allocator.used_bytes(),
+            })
+        })
+        .map_err(|err| err.to_string())
+}
+
+// What one stress run did, and what it left behind
+#[cfg(not(target_os = "none"))]
+#[derive(Debug)]
+pub struct StressReport {
+    pub threads: usize,
+    pub operations: usize,
+    pub failures: usize,
+    pub free_bytes: usize,
+    pub used_bytes: usize,
+}
+
+#[cfg(not(target_os = "none"))]
+impl core::fmt::Display for StressReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{} threads x {} operations: {} failed allocations, {} bytes free and {} in use after",
+            self.threads, self.operations, self.failures, self.free_bytes, self.used_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_pool_reuses_what_is_freed() {
+        let pool: Box<MetadataPool<{ 64 * 1024 }>> = Box::new(MetadataPool::new());
+        let mut live: Vec<(usize, Layout, u8)> = Vec::new();
+
+        // Many times the pool's size goes through it, never much of it at once
+        for round in 0..20_000usize {
+            let layout = Layout::from_size_align(1 + round * 7 % 300, 1 << (round % 4)).unwrap();
+            let start = pool.allocate(layout).unwrap();
+            assert!(pool.contains(start) && start.is_multiple_of(layout.align()));
+            let stamp = round as u8;
+            unsafe { core::ptr::write_bytes(start as *mut u8, stamp, layout.size()) };
+            live.push((start, layout, stamp));
+
+            if live.len() > 64 {
+                let (start, layout, stamp) = live.swap_remove(round * 31 % live.len());
+                // Nothing handed out since has written over it
+                let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, layout.size()) };
+                assert!(bytes.iter().all(|&byte| byte == stamp));
+                pool.free(start, layout).unwrap();
+            }
+        }
+
+        let too_big = Layout::from_size_align(128 * 1024, 1).unwrap();
+        assert_eq!(pool.allocate(too_big), Err(AllocError::InvalidLayout(too_big)));
+        let outside = Layout::from_size_align(8, 8).unwrap();
+        assert_eq!(pool.free(8, outside), Err(AllocError::UnknownAddress(8)));
+    }
+
+    #[test]
+    fn threads_never_share_a_block() {
+        let report = stress(8, 5_000).unwrap();
+        assert_eq!(report.used_bytes, 0);
+        assert_eq!(report.free_bytes, crate::USER_MEM_SIZE);
+    }
+}
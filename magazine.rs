@@ -0,0 +1,286 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use crate::locked::{LockedAllocator, SpinLock};
+use crate::slab::size_class;
+use crate::{AllocError, Allocator};
+#[cfg(not(target_os = "none"))]
+use crate::{AllocatorConfig, RegionLayout, Step, Workload};
+
+// Cached block sizes are powers of two from the minimum block up to this; anything
+// larger always goes to the shared allocator
+const MAX_CACHED_SIZE: usize = 64 * 1024;
+// Blocks one magazine holds. Refills and drains move half of that at once, so a
+// thread that alternates allocate and free stays clear of the lock.
+const MAGAZINE_SIZE: usize = 32;
+const BATCH: usize = MAGAZINE_SIZE / 2;
+// Live blocks per benchmark thread
+const WORKING_SET: usize = 256;
+
+// Recently freed blocks for one CPU or thread, a magazine per size class. Blocks in a
+// magazine are still allocated as far as the shared allocator is concerned; they only
+// go back to its free lists when a magazine overflows or the cache is flushed.
+#[derive(Debug)]
+pub struct MagazineCache {
+    min_block: usize,
+    // magazines[i] holds blocks of min_block << i bytes
+    magazines: Vec<Vec<usize>>,
+    // Times the shared lock was taken for a refill or drain
+    pub lock_trips: usize,
+}
+
+impl MagazineCache {
+    pub fn new(min_block: usize) -> Self {
+        let classes = (MAX_CACHED_SIZE / min_block).trailing_zeros() as usize + 1;
+        MagazineCache {
+            min_block,
+            magazines: vec![Vec::with_capacity(MAGAZINE_SIZE); classes],
+            lock_trips: 0,
+        }
+    }
+
+    // Magazine that serves layout and its block size
+    fn class_for(&self, layout: Layout) -> Option<(usize, usize)> {
+        size_class(layout, self.min_block, self.magazines.len())
+    }
+
+    pub fn allocate(&mut self, heap: &LockedAllocator, layout: Layout) -> Result<usize, AllocError> {
+        let (class, size) = match self.class_for(layout) {
+            Some(class) => class,
+            None => return heap.allocate(layout),
+        };
+
+        if self.magazines[class].is_empty() {
+            let block = Layout::from_size_align(size, size).unwrap();
+            let magazine = &mut self.magazines[class];
+            self.lock_trips += 1;
+            heap.with(|allocator| {
+                for _ in 0..BATCH {
+                    match allocator.allocate(block) {
+                        Ok(start) => magazine.push(start),
+                        // A partial refill still serves this request
+                        Err(err) if magazine.is_empty() => return Err(err),
+                        Err(_) => break,
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(self.magazines[class].pop().unwrap())
+    }
+
+    // layout has to be the one the block was allocated with, as for GlobalAlloc::dealloc.
+    // A block freed again while it is still in the magazine is refused here; once it has
+    // been drained, the shared allocator refuses it instead.
+    pub fn free(&mut self, heap: &LockedAllocator, start: usize, layout: Layout) -> Result<(), AllocError> {
+        let class = match self.class_for(layout) {
+            Some((class, _)) => class,
+            None => return heap.free(start),
+        };
+        if self.magazines[class].contains(&start) {
+            return Err(AllocError::DoubleFree(start));
+        }
+
+        if self.magazines[class].len() == MAGAZINE_SIZE {
+            let drained = self.magazines[class].split_off(MAGAZINE_SIZE - BATCH);
+            self.lock_trips += 1;
+            heap.with(|allocator| free_all(allocator, drained))?;
+        }
+        self.magazines[class].push(start);
+        Ok(())
+    }
+
+    // Gives every cached block back, e.g. before a CPU goes offline
+    pub fn flush(&mut self, heap: &LockedAllocator) -> Result<(), AllocError> {
+        let magazines = &mut self.magazines;
+        heap.with(|allocator| free_all(allocator, magazines.iter_mut().flat_map(|magazine| magazine.drain(..))))
+    }
+}
+
+// Frees every block, even past one the allocator refuses, so a double free doesn't leak
+// the blocks drained along with it. Returns the first refusal.
+fn free_all(allocator: &mut Allocator, blocks: impl IntoIterator<Item = usize>) -> Result<(), AllocError> {
+    blocks
+        .into_iter()
+        .map(|start| allocator.free_block(start))
+        .fold(Ok(()), Result::and)
+}
+
+// One MagazineCache per CPU in front of a shared LockedAllocator. A CPU only ever
+// takes its own cache's lock, so it is uncontended and only there to keep an interrupt
+// handler on the same CPU from getting in.
+pub struct PerCpuCaches<'a> {
+    heap: &'a LockedAllocator,
+    caches: Vec<SpinLock<MagazineCache>>,
+}
+
+impl<'a> PerCpuCaches<'a> {
+    pub fn new(heap: &'a LockedAllocator, cpus: usize, min_block: usize) -> Self {
+        PerCpuCaches {
+            heap,
+            caches: (0..cpus).map(|_| SpinLock::new(MagazineCache::new(min_block))).collect(),
+        }
+    }
+
+    pub fn allocate(&self, cpu: usize, layout: Layout) -> Result<usize, AllocError> {
+        self.caches[cpu].lock().allocate(self.heap, layout)
+    }
+
+    pub fn free(&self, cpu: usize, start: usize, layout: Layout) -> Result<(), AllocError> {
+        self.caches[cpu].lock().free(self.heap, start, layout)
+    }
+
+    pub fn flush(&self) -> Result<(), AllocError> {
+        self.caches.iter().try_for_each(|cache| cache.lock().flush(self.heap))
+    }
+
+    // Times any CPU took the shared lock for a refill or drain
+    pub fn lock_trips(&self) -> usize {
+        self.caches.iter().map(|cache| cache.lock().lock_trips).sum()
+    }
+}
+
+// `allocator bench [threads]`: the same random small allocation workload on 1 up to
+// threads threads, once straight against the locked allocator and once through
+// PerCpuCaches with a cache per thread, printing operations per second for each
+#[cfg(not(target_os = "none"))]
+pub fn benchmark(max_threads: usize, operations: usize) -> Result<(), AllocError> {
+    println!("threads  direct ops/s  cached ops/s  lock trips per 1000 ops");
+    let mut threads = 1;
+    while threads <= max_threads {
+        let (direct, _) = run_workload(threads, operations, false)?;
+        let (cached, trips) = run_workload(threads, operations, true)?;
+        println!(
+            "{:>7}  {:>12.0}  {:>12.0}  {:>23.1}",
+            threads,
+            direct,
+            cached,
+            trips as f64 * 1000.0 / (threads * operations) as f64
+        );
+        threads *= 2;
+    }
+    Ok(())
+}
+
+// Operations per second over all threads, and how often the cached run took the lock
+#[cfg(not(target_os = "none"))]
+fn run_workload(threads: usize, operations: usize, cached: bool) -> Result<(f64, usize), AllocError> {
+    let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned).seed(1);
+    let min_block = 1 << config.min_block_exp;
+    let heap = LockedAllocator::new();
+    heap.init(config)?;
+    // Each thread stands in for a CPU and only uses its own cache
+    let caches = PerCpuCaches::new(&heap, threads, min_block);
+
+    let started = std::time::Instant::now();
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|cpu| {
+                let (heap, caches) = (&heap, &caches);
+                scope.spawn(move || -> Result<(), AllocError> {
+                    let allocate = |layout| if cached { caches.allocate(cpu, layout) } else { heap.allocate(layout) };
+                    let free = |start, layout| if cached { caches.free(cpu, start, layout) } else { heap.free(start) };
+                    // Each thread keeps at most WORKING_SET blocks, so no thread count
+                    // runs the heap dry
+                    let mut workload = Workload::new(cpu as u64 + 1).max_size(8 * min_block).max_live(WORKING_SET);
+                    let mut live: Vec<(usize, Layout)> = Vec::new();
+
+                    for _ in 0..operations {
+                        match workload.next(live.len()) {
+                            Step::Free(index) => {
+                                let (start, layout) = live.swap_remove(index);
+                                free(start, layout)?;
+                            }
+                            Step::Allocate(layout) => live.push((allocate(layout)?, layout)),
+                            Step::Resize(..) => unreachable!("the mix has no resizes"),
+                        }
+                    }
+
+                    live.into_iter().try_for_each(|(start, layout)| free(start, layout))
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("benchmark thread panicked"))
+    })?;
+
+    let seconds = started.elapsed().as_secs_f64();
+    caches.flush()?;
+    heap.with(|allocator| allocator.check_free_lists())?;
+    Ok(((threads * operations) as f64 / seconds, caches.lock_trips()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_cpu_caches_give_everything_back_on_flush() {
+        let heap = LockedAllocator::new();
+        heap.init(AllocatorConfig::default().layout(RegionLayout::LargestAligned)).unwrap();
+        let caches = PerCpuCaches::new(&heap, 2, 4096);
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(1 << 20, 1).unwrap();
+
+        let mut held = Vec::new();
+        for cpu in [0, 1].repeat(MAGAZINE_SIZE * 2) {
+            held.push((cpu, caches.allocate(cpu, small).unwrap(), small));
+        }
+        held.push((1, caches.allocate(1, large).unwrap(), large));
+        let trips = caches.lock_trips();
+        // Freed and allocated again on the same CPU, the block comes straight back
+        let (cpu, start, layout) = held.pop().unwrap();
+        caches.free(cpu, start, layout).unwrap();
+        let (cpu, start, layout) = held.pop().unwrap();
+        caches.free(cpu, start, layout).unwrap();
+        assert_eq!(caches.allocate(cpu, layout), Ok(start));
+        assert_eq!(caches.lock_trips(), trips);
+
+        for (cpu, start, layout) in held.into_iter().chain([(cpu, start, layout)]) {
+            caches.free(cpu, start, layout).unwrap();
+        }
+        // Cached blocks are still allocated as far as the heap knows
+        assert!(heap.with(|allocator| Ok(allocator.used_bytes())).unwrap() > 0);
+        caches.flush().unwrap();
+        heap.with(|allocator| {
+            assert_eq!(allocator.used_bytes(), 0);
+            allocator.check_free_lists()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn double_frees_are_caught_in_the_magazine_and_after_draining() {
+        let heap = LockedAllocator::new();
+        heap.init(AllocatorConfig::default().layout(RegionLayout::LargestAligned)).unwrap();
+        let mut cache = MagazineCache::new(4096);
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let blocks: Vec<usize> = (0..=MAGAZINE_SIZE).map(|_| cache.allocate(&heap, small).unwrap()).collect();
+        // What the refills left over goes back, so the magazine starts out empty
+        cache.flush(&heap).unwrap();
+
+        cache.free(&heap, blocks[0], small).unwrap();
+        assert_eq!(cache.free(&heap, blocks[0], small), Err(AllocError::DoubleFree(blocks[0])));
+
+        // The last free overflows the magazine, which drains the half freed most recently
+        for &start in &blocks[1..] {
+            cache.free(&heap, start, small).unwrap();
+        }
+        let drained = blocks[MAGAZINE_SIZE - 1];
+        cache.free(&heap, drained, small).unwrap();
+        // The heap refuses it on the way back, depending on whether it has since coalesced
+        // into the block below, and still takes every other block
+        assert!(matches!(
+            cache.flush(&heap),
+            Err(AllocError::DoubleFree(start) | AllocError::UnknownAddress(start)) if start == drained
+        ));
+        heap.with(|allocator| {
+            assert_eq!(allocator.used_bytes(), 0);
+            allocator.check_free_lists()
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::io::{self, Write};
+
+use crate::Allocator;
+
+// Each character of the ASCII map covers 64 KiB, and each line 4 MiB
+const ASCII_CELL: usize = 64 * 1024;
+const ASCII_LINE_CELLS: usize = 64;
+
+// Each row of the SVG covers the same 4 MiB as a line of the ASCII map
+const SVG_ROW_BYTES: usize = ASCII_CELL * ASCII_LINE_CELLS;
+const SVG_WIDTH: usize = 1024;
+const SVG_ROW_HEIGHT: usize = 12;
+const SVG_LABEL_WIDTH: usize = 96;
+
+const FREE_COLOR: &str = "#66bb6a";
+const USED_COLOR: &str = "#ef5350";
+const UNTRACKED_COLOR: &str = "#e0e0e0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRow {
+    pub start: usize,
+    pub size: usize,
+    pub free: bool,
+}
+
+impl BlockRow {
+    fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+// Every block the allocator tracks, free or in use, in address order
+pub fn blocks(allocator: &Allocator) -> Vec<BlockRow> {
+    let free = allocator.free_addresses.iter().map(|(start, block)| BlockRow {
+        start: *start,
+        size: block.size(),
+        free: true,
+    });
+    let used = allocator.used_blocks.iter().map(|(start, block)| BlockRow {
+        start: *start,
+        size: block.size(),
+        free: false,
+    });
+
+    let mut rows: Vec<BlockRow> = free.chain(used).collect();
+    rows.sort_by_key(|row| row.start);
+    rows
+}
+
+// One line per 4 MiB, prefixed with its address. A cell is '.' if all of it is free,
+// '#' if all of it is in use, '+' if it holds both, and ' ' if the allocator tracks
+// none of it.
+pub fn write_ascii<W: Write>(allocator: &Allocator, out: &mut W) -> io::Result<()> {
+    let rows = blocks(allocator);
+    let region_start = allocator.config.region_start;
+    let region_end = region_start + allocator.config.region_size;
+
+    let mut next_row = 0;
+    let mut cell_start = region_start - region_start % ASCII_CELL;
+    let mut column = 0;
+
+    while cell_start < region_end {
+        if column == 0 {
+            write!(out, "0x{:012x} ", cell_start)?;
+        }
+
+        let cell_end = cell_start + ASCII_CELL;
+        while next_row < rows.len() && rows[next_row].end() <= cell_start {
+            next_row += 1;
+        }
+        let (mut free, mut used) = (false, false);
+        for row in rows[next_row..].iter().take_while(|row| row.start < cell_end) {
+            if row.free {
+                free = true;
+            } else {
+                used = true;
+            }
+        }
+        let cell = match (free, used) {
+            (true, false) => '.',
+            (false, true) => '#',
+            (true, true) => '+',
+            (false, false) => ' ',
+        };
+        write!(out, "{}", cell)?;
+
+        column += 1;
+        if column == ASCII_LINE_CELLS {
+            writeln!(out)?;
+            column = 0;
+        }
+        cell_start = cell_end;
+    }
+
+    if column != 0 {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+// The same layout as write_ascii, one row per 4 MiB, with free blocks in green, used
+// blocks in red and untracked memory in grey. Blocks that cross a row are drawn in
+// pieces.
+pub fn write_svg<W: Write>(allocator: &Allocator, out: &mut W) -> io::Result<()> {
+    let region_start = allocator.config.region_start;
+    let region_size = allocator.config.region_size;
+    let row_count = region_size.div_ceil(SVG_ROW_BYTES);
+    let scale = SVG_ROW_BYTES as f64 / SVG_WIDTH as f64;
+    let width = SVG_LABEL_WIDTH + SVG_WIDTH;
+    let height = row_count * SVG_ROW_HEIGHT;
+
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="10">"#,
+        width, height
+    )?;
+
+    for row in 0..row_count {
+        let y = row * SVG_ROW_HEIGHT;
+        let row_bytes = SVG_ROW_BYTES.min(region_size - row * SVG_ROW_BYTES);
+        writeln!(
+            out,
+            r#"<text x="0" y="{}">0x{:08x}</text>"#,
+            y + SVG_ROW_HEIGHT - 2,
+            region_start + row * SVG_ROW_BYTES
+        )?;
+        writeln!(
+            out,
+            r#"<rect x="{}" y="{}" width="{:.2}" height="{}" fill="{}"/>"#,
+            SVG_LABEL_WIDTH,
+            y,
+            row_bytes as f64 / scale,
+            SVG_ROW_HEIGHT - 1,
+            UNTRACKED_COLOR
+        )?;
+    }
+
+    for block in blocks(allocator) {
+        let mut offset = block.start - region_start;
+        let end = block.end() - region_start;
+
+        while offset < end {
+            let row = offset / SVG_ROW_BYTES;
+            let piece_end = end.min((row + 1) * SVG_ROW_BYTES);
+            writeln!(
+                out,
+                r#"<rect x="{:.2}" y="{}" width="{:.2}" height="{}" fill="{}"><title>0x{:x}-0x{:x} {} bytes {}</title></rect>"#,
+                SVG_LABEL_WIDTH as f64 + (offset % SVG_ROW_BYTES) as f64 / scale,
+                row * SVG_ROW_HEIGHT,
+                (piece_end - offset) as f64 / scale,
+                SVG_ROW_HEIGHT - 1,
+                if block.free { FREE_COLOR } else { USED_COLOR },
+                block.start,
+                block.end() - 1,
+                block.size,
+                if block.free { "free" } else { "used" }
+            )?;
+            offset = piece_end;
+        }
+    }
+
+    writeln!(out, "</svg>")
+}
+
+// start,end,size,free with one row per block; end is inclusive, as in allocate_block
+pub fn write_csv<W: Write>(allocator: &Allocator, out: &mut W) -> io::Result<()> {
+    writeln!(out, "start,end,size,free")?;
+    for block in blocks(allocator) {
+        writeln!(
+            out,
+            "0x{:x},0x{:x},{},{}",
+            block.start,
+            block.end() - 1,
+            block.size,
+            block.free
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllocatorConfig, FirstFit, RegionLayout, USER_MEM_START};
+
+    #[test]
+    fn maps_of_a_known_layout() {
+        // 8 MiB, so two lines of the ASCII map: a 64 KiB block and a page used at the
+        // start of the first line, and 64 KiB reserved a quarter of the way into the second
+        let config = AllocatorConfig::default()
+            .region(USER_MEM_START, 8 << 20)
+            .layout(RegionLayout::LargestAligned);
+        let mut allocator = Allocator::with_config(config).unwrap().with_policy(Box::new(FirstFit));
+        allocator.allocate_block(64 * 1024).unwrap();
+        allocator.allocate_block(4096).unwrap();
+        allocator.reserve(USER_MEM_START + (5 << 20), 64 * 1024).unwrap();
+
+        let mut ascii = Vec::new();
+        write_ascii(&allocator, &mut ascii).unwrap();
+        let expected = format!(
+            "0x000010000000 #+{}\n0x000010400000 {} {}\n",
+            ".".repeat(62),
+            ".".repeat(16),
+            ".".repeat(47)
+        );
+        assert_eq!(String::from_utf8(ascii).unwrap(), expected);
+
+        let mut csv = Vec::new();
+        write_csv(&allocator, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[..5],
+            [
+                "start,end,size,free",
+                "0x10000000,0x1000ffff,65536,false",
+                "0x10010000,0x10010fff,4096,false",
+                "0x10011000,0x10011fff,4096,true",
+                "0x10012000,0x10013fff,8192,true",
+            ]
+        );
+        // The reserved 64 KiB is the only gap between one block and the next
+        let rows = blocks(&allocator);
+        let gaps = rows
+            .windows(2)
+            .filter(|pair| pair[0].end() != pair[1].start)
+            .map(|pair| (pair[0].end(), pair[1].start))
+            .collect::<Vec<_>>();
+        assert_eq!(gaps, [(USER_MEM_START + (5 << 20), USER_MEM_START + (5 << 20) + 64 * 1024)]);
+        assert_eq!(lines.len(), rows.len() + 1);
+
+        let mut svg = Vec::new();
+        write_svg(&allocator, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="1120" height="24""#));
+        assert!(svg.contains(r#"<title>0x10000000-0x1000ffff 65536 bytes used</title>"#));
+        assert_eq!(svg.matches("<rect").count(), 2 + rows.len());
+        assert!(svg.ends_with("</svg>\n"));
+    }
+}
@@ -0,0 +1,176 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use crate::{AVLTree, AllocError, Allocator};
+
+// Objects up to the largest class are packed into slabs; anything bigger goes
+// straight to the page allocator
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// Of the given number of power of two size classes starting at min, the one that holds
+// layout, and its object size. Objects are aligned to their size, so any alignment up
+// to the size comes for free. Zero sized layouts and ones past the last class get None.
+pub fn size_class(layout: Layout, min: usize, classes: usize) -> Option<(usize, usize)> {
+    let size = layout.size().max(layout.align()).max(min).next_power_of_two();
+    let class = (size / min).trailing_zeros() as usize;
+    (layout.size() > 0 && class < classes).then_some((class, size))
+}
+
+// One slab is a single minimum size block from the page allocator, cut into equal
+// objects of its class. Objects are aligned to their size within the slab.
+#[derive(Debug)]
+struct Slab {
+    class: usize,
+    size: usize,
+    free_objects: Vec<usize>,
+    in_use: usize,
+}
+
+impl Slab {
+    fn new(class: usize, start: usize, size: usize) -> Slab {
+        let object_size = SIZE_CLASSES[class];
+        // Handed out from the end of the Vec, so reverse to fill from the low end
+        let free_objects = (start..start + size).step_by(object_size).rev().collect();
+        Slab {
+            class,
+            size,
+            free_objects,
+            in_use: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SlabAllocator {
+    // Every slab, keyed by its start address
+    slabs: AVLTree<usize, Slab>,
+    // Per class, the starts of the slabs that still have free objects
+    partial: Vec<Vec<usize>>,
+}
+
+impl SlabAllocator {
+    pub fn new() -> Self {
+        SlabAllocator {
+            slabs: AVLTree::new(),
+            partial: vec![Vec::new(); SIZE_CLASSES.len()],
+        }
+    }
+
+    pub fn allocate(&mut self, pages: &mut Allocator, layout: Layout) -> Result<usize, AllocError> {
+        let class = match size_class(layout, SIZE_CLASSES[0], SIZE_CLASSES.len()) {
+            Some((class, _)) => class,
+            None => return pages.allocate(layout),
+        };
+
+        let slab_start = match self.partial[class].last() {
+            Some(&start) => start,
+            None => {
+                let slab_size = pages.min_block_size();
+                let start = pages.allocate(Layout::from_size_align(slab_size, slab_size).unwrap())?;
+                self.slabs.insert(start, Slab::new(class, start, slab_size));
+                self.partial[class].push(start);
+                start
+            }
+        };
+
+        let slab = self.slabs.get_mut(&slab_start).unwrap();
+        let object = slab.free_objects.pop().unwrap();
+        slab.in_use += 1;
+        if slab.free_objects.is_empty() {
+            self.partial[class].pop();
+        }
+        Ok(object)
+    }
+
+    pub fn free(&mut self, pages: &mut Allocator, address: usize) -> Result<(), AllocError> {
+        let slab_start = match self.slabs.floor(&address) {
+            Some((start, slab)) if address < start + slab.size => *start,
+            _ => return pages.free_block(address),
+        };
+
+        let slab = self.slabs.get_mut(&slab_start).unwrap();
+        let object_size = SIZE_CLASSES[slab.class];
+        if !(address - slab_start).is_multiple_of(object_size) {
+            return Err(AllocError::UnknownAddress(address));
+        }
+        if slab.free_objects.contains(&address) {
+            return Err(AllocError::DoubleFree(address));
+        }
+
+        let was_full = slab.free_objects.is_empty();
+        slab.free_objects.push(address);
+        slab.in_use -= 1;
+        let class = slab.class;
+        let empty = slab.in_use == 0;
+
+        if was_full {
+            self.partial[class].push(slab_start);
+        }
+        // Only a slab with nothing left in it goes back to the page allocator
+        if empty {
+            self.partial[class].retain(|&start| start != slab_start);
+            self.slabs.remove(&slab_start);
+            pages.free_block(slab_start)?;
+        }
+        Ok(())
+    }
+
+    // Bytes of slab pages held, whether or not their objects are in use
+    pub fn slab_bytes(&self) -> usize {
+        self.slabs.iter().map(|(_, slab)| slab.size).sum()
+    }
+
+    // Bytes of objects currently handed out from slabs
+    pub fn object_bytes(&self) -> usize {
+        self.slabs
+            .iter()
+            .map(|(_, slab)| slab.in_use * SIZE_CLASSES[slab.class])
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllocatorConfig, RegionLayout};
+
+    #[test]
+    fn objects_are_reused_and_empty_slabs_go_back_to_the_pages() {
+        let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+        let mut pages = Allocator::with_config(config).unwrap();
+        let mut slabs = SlabAllocator::new();
+        let object = Layout::from_size_align(48, 8).unwrap();
+
+        // 48 bytes rounds up to the 64 byte class, packed from the low end of one page
+        let first = slabs.allocate(&mut pages, object).unwrap();
+        let second = slabs.allocate(&mut pages, object).unwrap();
+        assert_eq!(second, first + 64);
+        assert_eq!((slabs.slab_bytes(), slabs.object_bytes()), (pages.min_block_size(), 128));
+        // The object freed last is the one handed out next
+        slabs.free(&mut pages, first).unwrap();
+        assert_eq!(slabs.free(&mut pages, first), Err(AllocError::DoubleFree(first)));
+        assert_eq!(slabs.allocate(&mut pages, object), Ok(first));
+
+        // A full slab makes room for a second one
+        let per_slab = pages.min_block_size() / 64;
+        let mut live = vec![first, second];
+        live.extend((2..=per_slab).map(|_| slabs.allocate(&mut pages, object).unwrap()));
+        assert_eq!(slabs.slab_bytes(), 2 * pages.min_block_size());
+        assert_eq!(pages.used_bytes(), slabs.slab_bytes());
+
+        // Each slab's page goes back as soon as its last object does
+        let spill = live.pop().unwrap();
+        slabs.free(&mut pages, spill).unwrap();
+        assert_eq!(slabs.slab_bytes(), pages.min_block_size());
+        for object in live {
+            slabs.free(&mut pages, object).unwrap();
+        }
+        assert_eq!((slabs.slab_bytes(), pages.used_bytes()), (0, 0));
+
+        // Past the largest class, objects come straight from the page allocator
+        let large = slabs.allocate(&mut pages, Layout::from_size_align(4096, 8).unwrap()).unwrap();
+        assert_eq!((slabs.slab_bytes(), pages.used_bytes()), (0, 4096));
+        slabs.free(&mut pages, large).unwrap();
+    }
+}
@@ -0,0 +1,886 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::locked::SpinLock;
+use crate::{
+    policy_by_name, AllocCounters, AllocError, Allocator, AllocatorConfig, MemoryBlock, OwnerTag, RegionLayout,
+};
+
+// Bumped whenever the layout of either encoding changes. Only the current version is
+// read; a snapshot from any other is refused.
+const SNAPSHOT_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"ALOC";
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    // The binary encoding ended early
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u32),
+    // The encoding is readable but not a snapshot, e.g. a missing JSON field
+    Malformed(String),
+    // The snapshot decodes, but the state it describes could not have come from Allocator
+    Invariant(String),
+    Config(AllocError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadMagic => write!(f, "not an allocator snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "snapshot version {} is not supported", version)
+            }
+            SnapshotError::Malformed(reason) => write!(f, "malformed snapshot: {}", reason),
+            SnapshotError::Invariant(reason) => write!(f, "inconsistent snapshot: {}", reason),
+            SnapshotError::Config(err) => write!(f, "snapshot config rejected: {}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockRecord {
+    pub start: usize,
+    pub size: usize,
+    pub free: bool,
+    pub refs: usize,
+    pub owner: Option<OwnerTag>,
+    // Alignment a used block was allocated with; 1 for free blocks
+    pub align: usize,
+}
+
+// Everything needed to rebuild an Allocator: its config, its placement policy, the
+// state of every block in the region, the ranges reserved between them and the quotas
+// its owners are held to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub version: u32,
+    pub config: AllocatorConfig,
+    pub policy: String,
+    pub policy_state: usize,
+    pub counters: AllocCounters,
+    pub blocks: Vec<BlockRecord>,
+    // (start, length) in address order
+    pub reserved: Vec<(usize, usize)>,
+    // (owner, limit) in owner order
+    pub quotas: Vec<(OwnerTag, usize)>,
+}
+
+impl Snapshot {
+    pub fn capture(allocator: &Allocator) -> Snapshot {
+        let free = allocator.free_addresses.iter().map(|(start, block)| BlockRecord {
+            start: *start,
+            size: block.size(),
+            free: true,
+            refs: 0,
+            owner: None,
+            align: 1,
+        });
+        let used = allocator.used_blocks.iter().map(|(start, block)| BlockRecord {
+            start: *start,
+            size: block.size(),
+            free: false,
+            refs: block.refs,
+            owner: block.owner,
+            align: block.align,
+        });
+        let mut blocks: Vec<BlockRecord> = free.chain(used).collect();
+        blocks.sort_by_key(|block| block.start);
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            config: allocator.config.clone(),
+            policy: allocator.policy.name().to_string(),
+            policy_state: allocator.policy.state(),
+            counters: allocator.counters,
+            blocks,
+            reserved: allocator.reserved.iter().map(|(start, len)| (*start, *len)).collect(),
+            quotas: allocator.quotas.iter().map(|(tag, limit)| (*tag, *limit)).collect(),
+        }
+    }
+
+    // Rebuilds the allocator, refusing any snapshot that breaks an invariant Allocator
+    // keeps: the blocks and reserved ranges have to tile the region exactly, free blocks
+    // have to be buddy blocks of a valid order, and used blocks have to be whole minimum
+    // blocks that somebody still holds.
+    pub fn restore(&self) -> Result<Allocator, SnapshotError> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(self.version));
+        }
+        let mut allocator = Allocator::empty(self.config.clone()).map_err(SnapshotError::Config)?;
+        let policy = policy_by_name(&self.policy, self.policy_state)
+            .ok_or_else(|| SnapshotError::Invariant(format!("unknown policy {}", self.policy)))?;
+
+        let min_block = allocator.min_block_size();
+        let max_block = allocator.max_block_size();
+        // Allocator::empty has checked that the region doesn't overflow; the ranges in
+        // it have to be checked before their ends are taken
+        let region_end = self.config.region_start + self.config.region_size;
+        let inside = |start: usize, len: usize| start.checked_add(len).is_some_and(|end| end <= region_end);
+        let mut expected_start = self.config.region_start;
+        let mut reserved = self.reserved.iter().peekable();
+        let mut skip_reserved = |allocator: &mut Allocator, expected_start: &mut usize| {
+            while let Some(&&(start, len)) = reserved.peek().filter(|(start, _)| *start == *expected_start) {
+                if len == 0 || len % min_block != 0 || !inside(start, len) {
+                    return Err(SnapshotError::Invariant(format!(
+                        "reserved range at 0x{:x} is not whole minimum blocks inside the region",
+                        start
+                    )));
+                }
+                allocator.reserved.insert(start, len);
+                *expected_start += len;
+                reserved.next();
+            }
+            Ok(())
+        };
+
+        for block in &self.blocks {
+            skip_reserved(&mut allocator, &mut expected_start)?;
+            let invalid = |reason: &str| {
+                SnapshotError::Invariant(format!("block at 0x{:x}: {}", block.start, reason))
+            };
+            if block.start != expected_start {
+                return Err(invalid("blocks overlap or leave a gap"));
+            }
+            if block.size == 0 || block.size % min_block != 0 || !inside(block.start, block.size) {
+                return Err(invalid("size is not whole minimum blocks inside the region"));
+            }
+
+            if block.free {
+                if !block.size.is_power_of_two() || block.size > max_block || block.start % block.size != 0 {
+                    return Err(invalid("free block is not an aligned power of two"));
+                }
+                if block.refs != 0 || block.owner.is_some() || block.align != 1 {
+                    return Err(invalid("free block still carries allocation state"));
+                }
+                allocator.insert_free(MemoryBlock::from_range(block.start, block.size, true));
+            } else {
+                if block.refs == 0 {
+                    return Err(invalid("used block has no references"));
+                }
+                if !block.align.is_power_of_two() || block.align > max_block || block.start % block.align != 0 {
+                    return Err(invalid("used block is not at the alignment it was allocated with"));
+                }
+                let mut used = MemoryBlock::from_range(block.start, block.size, false);
+                used.refs = block.refs;
+                used.owner = block.owner;
+                used.align = block.align;
+                allocator.charge(block.owner, block.size);
+                allocator.used_blocks.insert(block.start, used);
+            }
+            expected_start += block.size;
+        }
+        skip_reserved(&mut allocator, &mut expected_start)?;
+        if let Some((start, _)) = reserved.next() {
+            return Err(SnapshotError::Invariant(format!(
+                "reserved range at 0x{:x} overlaps a block or lies outside the region",
+                start
+            )));
+        }
+
+        if expected_start != region_end {
+            return Err(SnapshotError::Invariant(format!(
+                "blocks end at 0x{:x}, region ends at 0x{:x}",
+                expected_start, region_end
+            )));
+        }
+
+        for &(tag, limit) in &self.quotas {
+            allocator.quotas.insert(tag, limit);
+        }
+        allocator.policy = policy;
+        allocator.counters = self.counters;
+        allocator
+            .check_free_lists()
+            .map_err(|err| SnapshotError::Invariant(err.to_string()))?;
+        Ok(allocator)
+    }
+
+    // Little endian throughout: magic, version, config, policy, counters,
+    // (start, size, free, refs, owner, align) for each block, (start, length) for each reserved
+    // range, then (owner, limit) for each quota
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&self.version.to_le_bytes());
+
+        put_u64(&mut out, self.config.region_start as u64);
+        put_u64(&mut out, self.config.region_size as u64);
+        out.extend_from_slice(&self.config.min_block_exp.to_le_bytes());
+        out.extend_from_slice(&self.config.max_block_exp.to_le_bytes());
+        out.push(self.config.seed.is_some() as u8);
+        put_u64(&mut out, self.config.seed.unwrap_or(0));
+        out.push(layout_code(self.config.layout));
+
+        put_u64(&mut out, self.policy.len() as u64);
+        out.extend_from_slice(self.policy.as_bytes());
+        put_u64(&mut out, self.policy_state as u64);
+
+        put_u64(&mut out, self.counters.allocations as u64);
+        put_u64(&mut out, self.counters.failures as u64);
+        put_u64(&mut out, self.counters.frees as u64);
+
+        put_u64(&mut out, self.blocks.len() as u64);
+        for block in &self.blocks {
+            put_u64(&mut out, block.start as u64);
+            put_u64(&mut out, block.size as u64);
+            out.push(block.free as u8);
+            put_u64(&mut out, block.refs as u64);
+            put_owner(&mut out, block.owner);
+            put_u64(&mut out, block.align as u64);
+        }
+
+        put_u64(&mut out, self.reserved.len() as u64);
+        for &(start, len) in &self.reserved {
+            put_u64(&mut out, start as u64);
+            put_u64(&mut out, len as u64);
+        }
+
+        put_u64(&mut out, self.quotas.len() as u64);
+        for &(tag, limit) in &self.quotas {
+            put_owner(&mut out, Some(tag));
+            put_u64(&mut out, limit as u64);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let region_start = reader.u64()? as usize;
+        let region_size = reader.u64()? as usize;
+        let min_block_exp = reader.u32()?;
+        let max_block_exp = reader.u32()?;
+        let has_seed = reader.u8()? != 0;
+        let seed = reader.u64()?;
+        let layout = layout_from_code(reader.u8()?)?;
+        let config = AllocatorConfig {
+            region_start,
+            region_size,
+            min_block_exp,
+            max_block_exp,
+            seed: if has_seed { Some(seed) } else { None },
+            layout,
+        };
+
+        let policy_len = reader.u64()? as usize;
+        let policy = String::from_utf8(reader.take(policy_len)?.to_vec())
+            .map_err(|_| SnapshotError::Malformed("policy name is not UTF-8".to_string()))?;
+        let policy_state = reader.u64()? as usize;
+        let counters = AllocCounters {
+            allocations: reader.u64()? as usize,
+            failures: reader.u64()? as usize,
+            frees: reader.u64()? as usize,
+        };
+
+        let count = reader.u64()? as usize;
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            let (start, size, free) = (reader.u64()? as usize, reader.u64()? as usize, reader.u8()? != 0);
+            let refs = reader.u64()? as usize;
+            let owner = reader.owner()?;
+            let align = reader.u64()? as usize;
+            blocks.push(BlockRecord { start, size, free, refs, owner, align });
+        }
+        let mut reserved = Vec::new();
+        for _ in 0..reader.u64()? {
+            reserved.push((reader.u64()? as usize, reader.u64()? as usize));
+        }
+        let mut quotas = Vec::new();
+        for _ in 0..reader.u64()? {
+            let tag = reader.owner()?.ok_or_else(|| SnapshotError::Malformed("quota has no owner".to_string()))?;
+            quotas.push((tag, reader.u64()? as usize));
+        }
+        if reader.position != bytes.len() {
+            return Err(SnapshotError::Malformed("trailing bytes".to_string()));
+        }
+
+        Ok(Snapshot {
+            version,
+            config,
+            policy,
+            policy_state,
+            counters,
+            blocks,
+            reserved,
+            quotas,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        out.push_str("{\n");
+        out.push_str(&format!("  \"version\": {},\n", self.version));
+        out.push_str(&format!("  \"region_start\": {},\n", self.config.region_start));
+        out.push_str(&format!("  \"region_size\": {},\n", self.config.region_size));
+        out.push_str(&format!("  \"min_block_exp\": {},\n", self.config.min_block_exp));
+        out.push_str(&format!("  \"max_block_exp\": {},\n", self.config.max_block_exp));
+        match self.config.seed {
+            Some(seed) => out.push_str(&format!("  \"seed\": {},\n", seed)),
+            None => out.push_str("  \"seed\": null,\n"),
+        }
+        out.push_str(&format!("  \"layout\": \"{}\",\n", layout_name(self.config.layout)));
+        out.push_str(&format!("  \"policy\": \"{}\",\n", self.policy));
+        out.push_str(&format!("  \"policy_state\": {},\n", self.policy_state));
+        out.push_str(&format!(
+            "  \"counters\": {{\"allocations\": {}, \"failures\": {}, \"frees\": {}}},\n",
+            self.counters.allocations, self.counters.failures, self.counters.frees
+        ));
+        out.push_str("  \"blocks\": [");
+        for (index, block) in self.blocks.iter().enumerate() {
+            out.push_str(if index == 0 { "\n" } else { ",\n" });
+            out.push_str(&format!(
+                "    {{\"start\": {}, \"size\": {}, \"free\": {}, \"refs\": {}, \"owner\": {}, \"align\": {}}}",
+                block.start,
+                block.size,
+                block.free,
+                block.refs,
+                owner_json(block.owner),
+                block.align
+            ));
+        }
+        out.push_str("\n  ],\n  \"reserved\": [");
+        for (index, (start, len)) in self.reserved.iter().enumerate() {
+            out.push_str(if index == 0 { "" } else { ", " });
+            out.push_str(&format!("[{}, {}]", start, len));
+        }
+        out.push_str("],\n  \"quotas\": [");
+        for (index, (tag, limit)) in self.quotas.iter().enumerate() {
+            out.push_str(if index == 0 { "" } else { ", " });
+            out.push_str(&format!("[{}, {}]", owner_json(Some(*tag)), limit));
+        }
+        out.push_str("]\n}\n");
+        out
+    }
+
+    pub fn from_json(text: &str) -> Result<Snapshot, SnapshotError> {
+        let mut parser = JsonParser { text: text.as_bytes(), position: 0 };
+        let root = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.text.len() {
+            return Err(SnapshotError::Malformed("trailing characters".to_string()));
+        }
+
+        let version = root.field("version")?.number()? as u32;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let seed = match root.field("seed")? {
+            Json::Null => None,
+            seed => Some(seed.number()?),
+        };
+        let config = AllocatorConfig {
+            region_start: root.field("region_start")?.number()? as usize,
+            region_size: root.field("region_size")?.number()? as usize,
+            min_block_exp: root.field("min_block_exp")?.number()? as u32,
+            max_block_exp: root.field("max_block_exp")?.number()? as u32,
+            seed,
+            layout: layout_from_name(root.field("layout")?.string()?)?,
+        };
+
+        let counters = root.field("counters")?;
+        let counters = AllocCounters {
+            allocations: counters.field("allocations")?.number()? as usize,
+            failures: counters.field("failures")?.number()? as usize,
+            frees: counters.field("frees")?.number()? as usize,
+        };
+
+        let mut blocks = Vec::new();
+        for block in root.field("blocks")?.array()? {
+            blocks.push(BlockRecord {
+                start: block.field("start")?.number()? as usize,
+                size: block.field("size")?.number()? as usize,
+                free: block.field("free")?.boolean()?,
+                refs: block.field("refs")?.number()? as usize,
+                owner: owner_from_json(block.field("owner")?)?,
+                align: block.field("align")?.number()? as usize,
+            });
+        }
+        let mut reserved = Vec::new();
+        for range in root.field("reserved")?.array()? {
+            match range.array()? {
+                [start, len] => reserved.push((start.number()? as usize, len.number()? as usize)),
+                _ => return Err(SnapshotError::Malformed("reserved range is not [start, length]".to_string())),
+            }
+        }
+        let mut quotas = Vec::new();
+        for quota in root.field("quotas")?.array()? {
+            match quota.array()? {
+                [tag, limit] => match owner_from_json(tag)? {
+                    Some(tag) => quotas.push((tag, limit.number()? as usize)),
+                    None => return Err(SnapshotError::Malformed("quota has no owner".to_string())),
+                },
+                _ => return Err(SnapshotError::Malformed("quota is not [owner, limit]".to_string())),
+            }
+        }
+
+        Ok(Snapshot {
+            version,
+            config,
+            policy: root.field("policy")?.string()?.to_string(),
+            policy_state: root.field("policy_state")?.number()? as usize,
+            counters,
+            blocks,
+            reserved,
+            quotas,
+        })
+    }
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+// A kind byte, 0 for no owner, then the subsystem or process id as a u32 or the
+// caller's name as a length and UTF-8 bytes
+fn put_owner(out: &mut Vec<u8>, owner: Option<OwnerTag>) {
+    match owner {
+        None => out.push(0),
+        Some(OwnerTag::Subsystem(id)) => {
+            out.push(1);
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        Some(OwnerTag::Process(pid)) => {
+            out.push(2);
+            out.extend_from_slice(&pid.to_le_bytes());
+        }
+        Some(OwnerTag::Caller(name)) => {
+            out.push(3);
+            put_u64(out, name.len() as u64);
+            out.extend_from_slice(name.as_bytes());
+        }
+    }
+}
+
+// null, or a [kind, id or name] pair
+fn owner_json(owner: Option<OwnerTag>) -> String {
+    match owner {
+        None => "null".to_string(),
+        Some(OwnerTag::Subsystem(id)) => format!("[\"subsystem\", {}]", id),
+        Some(OwnerTag::Process(pid)) => format!("[\"process\", {}]", pid),
+        Some(OwnerTag::Caller(name)) => {
+            format!("[\"caller\", \"{}\"]", name.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+    }
+}
+
+fn owner_from_json(value: &Json) -> Result<Option<OwnerTag>, SnapshotError> {
+    if let Json::Null = value {
+        return Ok(None);
+    }
+    let id = |value: &Json| {
+        u32::try_from(value.number()?).map_err(|_| SnapshotError::Malformed("owner id is out of range".to_string()))
+    };
+    match value.array()? {
+        [kind, value] => match kind.string()? {
+            "subsystem" => Ok(Some(OwnerTag::Subsystem(id(value)?))),
+            "process" => Ok(Some(OwnerTag::Process(id(value)?))),
+            "caller" => Ok(Some(caller(value.string()?))),
+            kind => Err(SnapshotError::Malformed(format!("unknown owner kind {}", kind))),
+        },
+        _ => Err(SnapshotError::Malformed("owner is not [kind, id]".to_string())),
+    }
+}
+
+// Every caller name read back from a snapshot so far
+static CALLERS: SpinLock<Vec<&'static str>> = SpinLock::new(Vec::new());
+
+// Caller names are &'static in OwnerTag, so a name read back from a snapshot has to live
+// that long. Each distinct name is leaked once, however many snapshots it turns up in.
+fn caller(name: &str) -> OwnerTag {
+    let mut callers = CALLERS.lock();
+    let name = match callers.iter().find(|known| **known == name) {
+        Some(known) => *known,
+        None => {
+            let leaked: &'static str = Box::leak(name.to_string().into_boxed_str());
+            callers.push(leaked);
+            leaked
+        }
+    };
+    OwnerTag::Caller(name)
+}
+
+fn layout_code(layout: RegionLayout) -> u8 {
+    match layout {
+        RegionLayout::Random => 0,
+        RegionLayout::LargestAligned => 1,
+    }
+}
+
+fn layout_from_code(code: u8) -> Result<RegionLayout, SnapshotError> {
+    match code {
+        0 => Ok(RegionLayout::Random),
+        1 => Ok(RegionLayout::LargestAligned),
+        _ => Err(SnapshotError::Malformed(format!("unknown layout {}", code))),
+    }
+}
+
+fn layout_name(layout: RegionLayout) -> &'static str {
+    match layout {
+        RegionLayout::Random => "random",
+        RegionLayout::LargestAligned => "largest-aligned",
+    }
+}
+
+fn layout_from_name(name: &str) -> Result<RegionLayout, SnapshotError> {
+    match name {
+        "random" => Ok(RegionLayout::Random),
+        "largest-aligned" => Ok(RegionLayout::LargestAligned),
+        _ => Err(SnapshotError::Malformed(format!("unknown layout {}", name))),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.position.checked_add(len).ok_or(SnapshotError::Truncated)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(SnapshotError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn owner(&mut self) -> Result<Option<OwnerTag>, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(OwnerTag::Subsystem(self.u32()?))),
+            2 => Ok(Some(OwnerTag::Process(self.u32()?))),
+            3 => {
+                let len = self.u64()? as usize;
+                let name = core::str::from_utf8(self.take(len)?)
+                    .map_err(|_| SnapshotError::Malformed("caller name is not UTF-8".to_string()))?;
+                Ok(Some(caller(name)))
+            }
+            kind => Err(SnapshotError::Malformed(format!("unknown owner kind {}", kind))),
+        }
+    }
+}
+
+// Just enough JSON for snapshots: numbers are unsigned integers and strings have no
+// escapes beyond \" and \\
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn field(&self, name: &str) -> Result<&Json, SnapshotError> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| SnapshotError::Malformed(format!("missing field {}", name))),
+            _ => Err(SnapshotError::Malformed(format!("expected an object around {}", name))),
+        }
+    }
+
+    fn number(&self) -> Result<u64, SnapshotError> {
+        match self {
+            Json::Number(number) => Ok(*number),
+            _ => Err(SnapshotError::Malformed("expected a number".to_string())),
+        }
+    }
+
+    fn boolean(&self) -> Result<bool, SnapshotError> {
+        match self {
+            Json::Bool(value) => Ok(*value),
+            _ => Err(SnapshotError::Malformed("expected true or false".to_string())),
+        }
+    }
+
+    fn string(&self) -> Result<&str, SnapshotError> {
+        match self {
+            Json::String(value) => Ok(value),
+            _ => Err(SnapshotError::Malformed("expected a string".to_string())),
+        }
+    }
+
+    fn array(&self) -> Result<&[Json], SnapshotError> {
+        match self {
+            Json::Array(values) => Ok(values),
+            _ => Err(SnapshotError::Malformed("expected an array".to_string())),
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, expected: &str) -> SnapshotError {
+        SnapshotError::Malformed(format!("expected {} at offset {}", expected, self.position))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.position).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.position).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), SnapshotError> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("'{}'", c as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, SnapshotError> {
+        if !self.text[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error(word));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, SnapshotError> {
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(c) if c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, SnapshotError> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("a field name"));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, SnapshotError> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(Json::Array(values));
+                }
+                _ => return Err(self.error("',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, SnapshotError> {
+        self.expect(b'"')?;
+        let mut value = Vec::new();
+        loop {
+            match self.text.get(self.position) {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    match self.text.get(self.position + 1) {
+                        Some(&c @ (b'"' | b'\\')) => value.push(c),
+                        _ => return Err(self.error("\\\" or \\\\")),
+                    }
+                    self.position += 2;
+                }
+                Some(&c) => {
+                    value.push(c);
+                    self.position += 1;
+                }
+                None => return Err(self.error("a closing '\"'")),
+            }
+        }
+        self.position += 1;
+        String::from_utf8(value).map_err(|_| self.error("UTF-8"))
+    }
+
+    fn number(&mut self) -> Result<Json, SnapshotError> {
+        let start = self.position;
+        while self.text.get(self.position).is_some_and(u8::is_ascii_digit) {
+            self.position += 1;
+        }
+        core::str::from_utf8(&self.text[start..self.position])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .map(Json::Number)
+            .ok_or_else(|| self.error("an unsigned integer"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::Layout;
+
+    const OWNERS: [OwnerTag; 3] = [OwnerTag::Subsystem(3), OwnerTag::Process(1), OwnerTag::Caller("say \"hi\"")];
+
+    // A heap with shared, tagged and free memory in it
+    fn fragmented() -> Allocator {
+        let config = AllocatorConfig::default().seed(3);
+        let mut allocator = Allocator::with_config(config).unwrap();
+        allocator.set_quota(OWNERS[0], Some(1 << 20));
+        let mut live = Vec::new();
+        for size in (1..40).map(|i| i * 5_000) {
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            let start = match OWNERS.get(size / 5_000 % 5) {
+                Some(tag) if size < 100_000 => allocator.allocate_tagged(layout, *tag),
+                _ => allocator.allocate(layout),
+            };
+            live.push(start.unwrap());
+        }
+        for start in live.iter().step_by(3) {
+            allocator.free_block(*start).unwrap();
+        }
+        allocator.share_block(live[1]).unwrap();
+        allocator.allocate(Layout::from_size_align(5_000, 1 << 16).unwrap()).unwrap();
+        allocator
+    }
+
+    #[test]
+    fn both_encodings_round_trip() {
+        let allocator = fragmented();
+        let snapshot = Snapshot::capture(&allocator);
+
+        let from_bytes = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        let from_json = Snapshot::from_json(&snapshot.to_json()).unwrap();
+        assert_eq!(from_bytes, snapshot);
+        assert_eq!(from_json, snapshot);
+
+        let mut restored = from_json.restore().unwrap();
+        assert_eq!(Snapshot::capture(&restored), snapshot);
+        assert_eq!(restored.free_bytes(), allocator.free_bytes());
+        assert_eq!(restored.leak_report(), allocator.leak_report());
+        for tag in OWNERS {
+            assert!(restored.owner_bytes(tag) > 0);
+            assert_eq!(restored.owner_bytes(tag), allocator.owner_bytes(tag));
+        }
+        let over = Layout::from_size_align(1 << 20, 1).unwrap();
+        assert!(matches!(
+            restored.allocate_tagged(over, OWNERS[0]),
+            Err(AllocError::QuotaExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn restore_refuses_overlapping_blocks() {
+        let mut snapshot = Snapshot::capture(&fragmented());
+        snapshot.blocks[1].start -= 4096;
+        assert!(matches!(snapshot.restore(), Err(SnapshotError::Invariant(_))));
+
+        let json = Snapshot::capture(&fragmented()).to_json().replace("\"free\": true", "\"free\": 1");
+        assert!(matches!(Snapshot::from_json(&json), Err(SnapshotError::Malformed(_))));
+
+        let mut snapshot = Snapshot::capture(&fragmented());
+        let used = snapshot.blocks.iter_mut().find(|block| !block.free).unwrap();
+        // One step more alignment than its address has
+        used.align = 2 << used.start.trailing_zeros();
+        assert!(matches!(snapshot.restore(), Err(SnapshotError::Invariant(_))));
+    }
+
+    #[test]
+    fn restore_refuses_ranges_that_run_past_the_address_space() {
+        let mut snapshot = Snapshot::capture(&fragmented());
+        snapshot.blocks[0].size = usize::MAX & !0xfff;
+        let decoded = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert!(matches!(decoded.restore(), Err(SnapshotError::Invariant(_))));
+
+        let mut snapshot = Snapshot::capture(&fragmented());
+        snapshot.reserved.push((snapshot.blocks[0].start, usize::MAX & !0xfff));
+        snapshot.reserved.sort();
+        let decoded = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert!(matches!(decoded.restore(), Err(SnapshotError::Invariant(_))));
+    }
+
+    #[test]
+    fn caller_names_are_leaked_once_however_often_they_are_read() {
+        let bytes = Snapshot::capture(&fragmented()).to_bytes();
+        let names = || {
+            let decoded = Snapshot::from_bytes(&bytes).unwrap();
+            decoded
+                .blocks
+                .iter()
+                .filter_map(|block| match block.owner {
+                    Some(OwnerTag::Caller(name)) => Some(name),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let (first, second) = (names(), names());
+        assert!(!first.is_empty());
+        assert!(first.iter().chain(&second).all(|name| core::ptr::eq(*name, first[0])));
+    }
+
+    #[test]
+    fn alignment_is_recorded_and_other_versions_are_refused() {
+        let snapshot = Snapshot::capture(&fragmented());
+        let used: Vec<&BlockRecord> = snapshot.blocks.iter().filter(|block| !block.free).collect();
+        assert!(used.iter().any(|block| block.align == 1 << 16));
+        assert!(used.iter().any(|block| block.align == 1 && block.start.trailing_zeros() >= 16));
+
+        let json = snapshot.to_json().replace("\"version\": 1", "\"version\": 2");
+        assert!(matches!(Snapshot::from_json(&json), Err(SnapshotError::UnsupportedVersion(2))));
+        let mut bytes = snapshot.to_bytes();
+        bytes[4..8].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(Snapshot::from_bytes(&bytes), Err(SnapshotError::UnsupportedVersion(0))));
+    }
+}
@@ -0,0 +1,387 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use crate::{AllocError, AllocatorConfig};
+#[cfg(not(target_os = "none"))]
+use crate::{Allocator, Step, Workload};
+
+// Each first level (a power of two range of sizes) is split into 2^SL_LOG2 second
+// level lists of equal width
+const SL_LOG2: u32 = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+const FL_COUNT: usize = usize::BITS as usize;
+
+// Header for the block starting at a unit. The region is measured in units of the
+// minimum block size and there is one header per unit, so any block is found from its
+// address without a search. size is zero for units that don't start a block.
+#[derive(Debug, Clone, Copy, Default)]
+struct BlockHeader {
+    size: usize,
+    free: bool,
+    prev_phys: Option<usize>,
+    prev_free: Option<usize>,
+    next_free: Option<usize>,
+}
+
+// Two-level segregated fit over the same region as Allocator. The first level picks
+// the power of two range of a size and the second level a slice of that range; a
+// bitmap per level finds a non-empty list in constant time, so allocate and free do a
+// bounded amount of work whatever the state of the heap.
+#[derive(Debug)]
+pub struct TlsfAllocator {
+    region_start: usize,
+    unit: usize,
+    headers: Vec<BlockHeader>,
+    fl_bitmap: u64,
+    sl_bitmap: [u32; FL_COUNT],
+    heads: [[Option<usize>; SL_COUNT]; FL_COUNT],
+    // Free list links and unlinks, the unit of work that TLSF keeps constant
+    list_ops: usize,
+}
+
+impl TlsfAllocator {
+    pub fn new(config: AllocatorConfig) -> Result<Self, AllocError> {
+        config.validate()?;
+
+        let unit = 1 << config.min_block_exp;
+        let units = config.region_size / unit;
+        let mut tlsf = TlsfAllocator {
+            region_start: config.region_start,
+            unit,
+            headers: vec![BlockHeader::default(); units],
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            heads: [[None; SL_COUNT]; FL_COUNT],
+            list_ops: 0,
+        };
+
+        tlsf.headers[0].size = units;
+        tlsf.insert_free(0);
+        Ok(tlsf)
+    }
+
+    // First and second level lists that hold blocks of units
+    fn mapping(units: usize) -> (usize, usize) {
+        if units < SL_COUNT {
+            return (0, units);
+        }
+        let log2 = usize::BITS - 1 - units.leading_zeros();
+        let fl = (log2 - SL_LOG2 + 1) as usize;
+        let sl = (units >> (log2 - SL_LOG2)) - SL_COUNT;
+        (fl, sl)
+    }
+
+    // Rounds units up to the next list boundary, so any block in the list found is
+    // large enough without looking at it
+    fn mapping_search(units: usize) -> (usize, usize) {
+        if units < SL_COUNT {
+            return (0, units);
+        }
+        let log2 = usize::BITS - 1 - units.leading_zeros();
+        Self::mapping(units + (1 << (log2 - SL_LOG2)) - 1)
+    }
+
+    fn insert_free(&mut self, index: usize) {
+        let (fl, sl) = Self::mapping(self.headers[index].size);
+        let head = self.heads[fl][sl];
+
+        self.headers[index].free = true;
+        self.headers[index].prev_free = None;
+        self.headers[index].next_free = head;
+        if let Some(head) = head {
+            self.headers[head].prev_free = Some(index);
+        }
+
+        self.heads[fl][sl] = Some(index);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+        self.list_ops += 1;
+    }
+
+    fn remove_free(&mut self, index: usize) {
+        let (fl, sl) = Self::mapping(self.headers[index].size);
+        let BlockHeader { prev_free, next_free, .. } = self.headers[index];
+
+        match prev_free {
+            Some(prev) => self.headers[prev].next_free = next_free,
+            None => self.heads[fl][sl] = next_free,
+        }
+        if let Some(next) = next_free {
+            self.headers[next].prev_free = prev_free;
+        }
+
+        if self.heads[fl][sl].is_none() {
+            self.sl_bitmap[fl] &= !(1 << sl);
+            if self.sl_bitmap[fl] == 0 {
+                self.fl_bitmap &= !(1 << fl);
+            }
+        }
+        self.headers[index].free = false;
+        self.list_ops += 1;
+    }
+
+    // Head of the first non-empty list at or above (fl, sl)
+    fn find_suitable(&self, fl: usize, sl: usize) -> Option<usize> {
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let mut fl = fl;
+        let mut sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0u64).checked_shl(fl as u32 + 1).unwrap_or(0);
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+        self.heads[fl][sl_map.trailing_zeros() as usize]
+    }
+
+    fn next_phys(&self, index: usize) -> Option<usize> {
+        let next = index + self.headers[index].size;
+        Some(next).filter(|&next| next < self.headers.len())
+    }
+
+    // Cuts the block at index down to units, making the rest a block of its own
+    fn split(&mut self, index: usize, units: usize) -> usize {
+        let rest = index + units;
+        self.headers[rest] = BlockHeader {
+            size: self.headers[index].size - units,
+            prev_phys: Some(index),
+            ..BlockHeader::default()
+        };
+        self.headers[index].size = units;
+        if let Some(next) = self.next_phys(rest) {
+            self.headers[next].prev_phys = Some(rest);
+        }
+        rest
+    }
+
+    // Folds the block at next into the block at index, which comes right before it
+    fn absorb(&mut self, index: usize, next: usize) {
+        self.headers[index].size += self.headers[next].size;
+        self.headers[next] = BlockHeader::default();
+        if let Some(after) = self.next_phys(index) {
+            self.headers[after].prev_phys = Some(index);
+        }
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> Result<usize, AllocError> {
+        if layout.size() == 0 {
+            return Err(AllocError::InvalidLayout(layout));
+        }
+        let units = layout.size().div_ceil(self.unit);
+        let align_units = (layout.align() / self.unit).max(1);
+        // Over-ask by the alignment so the aligned start always fits
+        let search = units + align_units - 1;
+
+        let (fl, sl) = Self::mapping_search(search);
+        let mut index = self
+            .find_suitable(fl, sl)
+            .ok_or(AllocError::OutOfMemory(layout))?;
+        self.remove_free(index);
+
+        let address = self.region_start + index * self.unit;
+        let aligned = (address + layout.align() - 1) & !(layout.align() - 1);
+        let lead = (aligned - address) / self.unit;
+        if lead > 0 {
+            // The block before a free block is never free, so the lead stands alone
+            let rest = self.split(index, lead);
+            self.insert_free(index);
+            index = rest;
+        }
+
+        if self.headers[index].size > units {
+            let tail = self.split(index, units);
+            self.insert_free(tail);
+        }
+
+        Ok(aligned)
+    }
+
+    pub fn free_block(&mut self, start: usize) -> Result<(), AllocError> {
+        let offset = start.wrapping_sub(self.region_start);
+        if start < self.region_start || !offset.is_multiple_of(self.unit) || offset / self.unit >= self.headers.len() {
+            return Err(AllocError::UnknownAddress(start));
+        }
+        let mut index = offset / self.unit;
+        match self.headers[index] {
+            BlockHeader { size: 0, .. } => return Err(AllocError::UnknownAddress(start)),
+            BlockHeader { free: true, .. } => return Err(AllocError::DoubleFree(start)),
+            _ => {}
+        }
+
+        if let Some(next) = self.next_phys(index).filter(|&next| self.headers[next].free) {
+            self.remove_free(next);
+            self.absorb(index, next);
+        }
+        if let Some(prev) = self.headers[index].prev_phys.filter(|&prev| self.headers[prev].free) {
+            self.remove_free(prev);
+            self.absorb(prev, index);
+            index = prev;
+        }
+
+        self.insert_free(index);
+        Ok(())
+    }
+
+    fn blocks(&self) -> impl Iterator<Item = &BlockHeader> + '_ {
+        let mut index = Some(0);
+        core::iter::from_fn(move || {
+            let current = index?;
+            index = self.next_phys(current);
+            Some(&self.headers[current])
+        })
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.blocks().filter(|block| block.free).map(|block| block.size * self.unit).sum()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.blocks().filter(|block| !block.free).map(|block| block.size * self.unit).sum()
+    }
+
+    pub fn free_block_count(&self) -> usize {
+        self.blocks().filter(|block| block.free).count()
+    }
+
+    pub fn largest_free_block(&self) -> usize {
+        self.blocks()
+            .filter(|block| block.free)
+            .map(|block| block.size * self.unit)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+// Outcome of one backend running the shared workload in compare_backends
+#[derive(Debug, Default)]
+struct WorkloadResult {
+    failures: usize,
+    free_blocks: usize,
+    largest_free: usize,
+    free_bytes: usize,
+    used_bytes: usize,
+    // Units of work done by all allocates and frees, and the most done by one of them
+    total_work: usize,
+    worst_op: usize,
+}
+
+impl WorkloadResult {
+    // 1 - largest / free: zero when the free memory is one block
+    fn fragmentation(&self) -> f64 {
+        if self.free_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free as f64 / self.free_bytes as f64
+    }
+
+    fn record(&mut self, work: usize) {
+        self.total_work += work;
+        self.worst_op = self.worst_op.max(work);
+    }
+}
+
+// Replays one random workload against Allocator and TlsfAllocator over the same region.
+// Work is free list links and unlinks for TLSF, and tree nodes visited plus rotations
+// for Allocator; the tree only counts those in test builds, so outside of tests the
+// tree's work stays zero.
+#[cfg(not(target_os = "none"))]
+fn run_workload(
+    config: AllocatorConfig,
+    seed: u64,
+    operations: usize,
+) -> Result<(WorkloadResult, WorkloadResult), AllocError> {
+    let mut tree = Allocator::with_config(config.clone())?;
+    let mut tlsf = TlsfAllocator::new(config)?;
+    let mut tree_result = WorkloadResult::default();
+    let mut tlsf_result = WorkloadResult::default();
+    let mut tree_live = Vec::new();
+    let mut tlsf_live = Vec::new();
+    let mut workload = Workload::new(seed);
+
+    for _ in 0..operations {
+        let step = workload.next(tree_live.len().min(tlsf_live.len()));
+        #[cfg(test)]
+        let tree_before = crate::tree_visits();
+        let tlsf_before = tlsf.list_ops;
+
+        match step {
+            Step::Free(index) => {
+                tree.free_block(tree_live.swap_remove(index))?;
+                tlsf.free_block(tlsf_live.swap_remove(index))?;
+            }
+            Step::Allocate(layout) => {
+                match tree.allocate(layout) {
+                    Ok(start) => tree_live.push(start),
+                    Err(_) => tree_result.failures += 1,
+                }
+                match tlsf.allocate(layout) {
+                    Ok(start) => tlsf_live.push(start),
+                    Err(_) => tlsf_result.failures += 1,
+                }
+            }
+            Step::Resize(..) => unreachable!("the mix has no resizes"),
+        }
+
+        #[cfg(test)]
+        tree_result.record(crate::tree_visits() - tree_before);
+        tlsf_result.record(tlsf.list_ops - tlsf_before);
+    }
+
+    tree_result.free_blocks = tree.free_block_count();
+    tree_result.free_bytes = tree.free_bytes();
+    tree_result.used_bytes = tree.used_bytes();
+    // Buddy blocks stop at the largest order, so measure runs of adjacent free blocks
+    tree_result.largest_free = tree.largest_free_run();
+    tlsf_result.free_blocks = tlsf.free_block_count();
+    tlsf_result.free_bytes = tlsf.free_bytes();
+    tlsf_result.used_bytes = tlsf.used_bytes();
+    tlsf_result.largest_free = tlsf.largest_free_block();
+    Ok((tree_result, tlsf_result))
+}
+
+// Prints how fragmented each backend ends up after the same workload. How much work
+// each takes per operation is compared by the test below.
+#[cfg(not(target_os = "none"))]
+pub fn compare_backends(config: AllocatorConfig, seed: u64, operations: usize) -> Result<(), AllocError> {
+    let (tree, tlsf) = run_workload(config, seed, operations)?;
+    for (name, result) in [("tree", &tree), ("tlsf", &tlsf)] {
+        println!(
+            "{}: {} failures, {} bytes used in the end, {} free blocks, fragmentation {:.3}",
+            name,
+            result.failures,
+            result.used_bytes,
+            result.free_blocks,
+            result.fragmentation()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RegionLayout;
+
+    #[test]
+    fn tlsf_does_bounded_work_and_fragments_no_worse_than_the_tree() {
+        let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+        for seed in 1..=4 {
+            let (tree, tlsf) = run_workload(config.clone(), seed, 20_000).unwrap();
+
+            for result in [&tree, &tlsf] {
+                assert_eq!(result.free_bytes + result.used_bytes, config.region_size);
+            }
+            // A free takes out at most both neighbours and puts one block back; an
+            // allocate takes one block and puts back at most a lead and a tail
+            assert!(tlsf.worst_op <= 3);
+            assert!(tree.worst_op > tlsf.worst_op);
+            assert!(tree.total_work > tlsf.total_work);
+            assert!(tlsf.fragmentation() <= tree.fragmentation());
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt;
+
+use crate::{AVLTree, AllocError, Allocator};
+#[cfg(not(target_os = "none"))]
+use crate::{policy_by_name, AllocatorConfig, RegionLayout, Step, Workload};
+
+// How many fragmentation samples a replay takes over the whole trace
+const TIMELINE_SAMPLES: usize = 20;
+
+// One line of a trace. Ids name allocations for the whole trace, so a free or realloc
+// finds its block wherever the allocator put it on this run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    Alloc { id: usize, size: usize, align: usize },
+    Free { id: usize },
+    Realloc { id: usize, size: usize },
+}
+
+// A line based trace:
+//
+//     region <start> <size>
+//     alloc <id> <size> <align>
+//     realloc <id> <size>
+//     free <id>
+//
+// Numbers are decimal or 0x prefixed hex, blank lines and lines starting with # are
+// skipped, and the region line is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub region: Option<(usize, usize)>,
+    pub events: Vec<TraceEvent>,
+}
+
+#[derive(Debug)]
+pub struct TraceError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trace line {}: {}", self.line, self.reason)
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((start, size)) = self.region {
+            writeln!(f, "region 0x{:x} 0x{:x}", start, size)?;
+        }
+        for event in &self.events {
+            match event {
+                TraceEvent::Alloc { id, size, align } => writeln!(f, "alloc {} {} {}", id, size, align)?,
+                TraceEvent::Free { id } => writeln!(f, "free {}", id)?,
+                TraceEvent::Realloc { id, size } => writeln!(f, "realloc {} {}", id, size)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Trace {
+    pub fn parse(text: &str) -> Result<Trace, TraceError> {
+        let mut trace = Trace::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let error = |reason: &str| TraceError {
+                line: index + 1,
+                reason: reason.to_string(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let op = words.next().unwrap();
+            let numbers = words
+                .map(parse_number)
+                .collect::<Option<Vec<usize>>>()
+                .ok_or_else(|| error("expected numbers after the operation"))?;
+
+            match (op, numbers.as_slice()) {
+                ("region", &[start, size]) => trace.region = Some((start, size)),
+                ("alloc", &[id, size, align]) => {
+                    if !align.is_power_of_two() {
+                        return Err(error("alignment is not a power of two"));
+                    }
+                    trace.events.push(TraceEvent::Alloc { id, size, align });
+                }
+                ("free", &[id]) => trace.events.push(TraceEvent::Free { id }),
+                ("realloc", &[id, size]) => trace.events.push(TraceEvent::Realloc { id, size }),
+                ("region" | "alloc" | "free" | "realloc", _) => {
+                    return Err(error(&format!("wrong number of arguments to {}", op)))
+                }
+                _ => return Err(error(&format!("unknown operation {}", op))),
+            }
+        }
+
+        Ok(trace)
+    }
+}
+
+fn parse_number(word: &str) -> Option<usize> {
+    match word.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+// Turns calls on a live Allocator into trace events, giving each allocation the next
+// id and remembering which id lives at which address
+#[derive(Debug)]
+pub struct TraceRecorder {
+    trace: Trace,
+    next_id: usize,
+    ids: AVLTree<usize, usize>,
+}
+
+impl TraceRecorder {
+    pub fn new(region_start: usize, region_size: usize) -> Self {
+        TraceRecorder {
+            trace: Trace {
+                region: Some((region_start, region_size)),
+                events: Vec::new(),
+            },
+            next_id: 0,
+            ids: AVLTree::new(),
+        }
+    }
+
+    pub fn alloc(&mut self, address: usize, layout: Layout) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(address, id);
+        self.trace.events.push(TraceEvent::Alloc {
+            id,
+            size: layout.size(),
+            align: layout.align(),
+        });
+    }
+
+    // Blocks allocated before recording started have no id and are left out
+    pub fn free(&mut self, address: usize) {
+        if let Some(id) = self.ids.remove(&address) {
+            self.trace.events.push(TraceEvent::Free { id });
+        }
+    }
+
+    pub fn realloc(&mut self, from: usize, to: usize, size: usize) {
+        if let Some(id) = self.ids.remove(&from) {
+            self.ids.insert(to, id);
+            self.trace.events.push(TraceEvent::Realloc { id, size });
+        }
+    }
+
+    // A block moved by Allocator::compact keeps its id. Nothing is recorded, since the
+    // caller asked for no change to its size.
+    pub fn moved(&mut self, from: usize, to: usize) {
+        if let Some(id) = self.ids.remove(&from) {
+            self.ids.insert(to, id);
+        }
+    }
+
+    pub fn finish(self) -> Trace {
+        self.trace
+    }
+}
+
+// One point on the fragmentation timeline of a replay
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimelineSample {
+    pub operation: usize,
+    pub used_bytes: usize,
+    pub fragmentation: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    pub policy: &'static str,
+    pub operations: usize,
+    pub failures: usize,
+    pub peak_used_bytes: usize,
+    pub timeline: Vec<TimelineSample>,
+    // Deepest of the size and address trees after each operation
+    pub depths: Vec<usize>,
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let max_depth = self.depths.iter().max().copied().unwrap_or(0);
+        let mean_depth = if self.depths.is_empty() {
+            0.0
+        } else {
+            self.depths.iter().sum::<usize>() as f64 / self.depths.len() as f64
+        };
+        writeln!(
+            f,
+            "{}: {} operations, {} failures, peak {} bytes used, tree depth {:.1} mean / {} max",
+            self.policy, self.operations, self.failures, self.peak_used_bytes, mean_depth, max_depth
+        )?;
+        for sample in &self.timeline {
+            writeln!(
+                f,
+                "  op {:>8}: {:>10} bytes used, fragmentation {:.3}",
+                sample.operation, sample.used_bytes, sample.fragmentation
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Runs the trace against allocator. Frees and reallocs of ids whose allocation failed
+// are skipped; a failed realloc leaves the block where it was.
+pub fn replay(allocator: &mut Allocator, trace: &Trace) -> Result<ReplayReport, AllocError> {
+    let mut report = ReplayReport {
+        policy: allocator.policy.name(),
+        operations: trace.events.len(),
+        failures: 0,
+        peak_used_bytes: 0,
+        timeline: Vec::new(),
+        depths: Vec::with_capacity(trace.events.len()),
+    };
+    // id -> (address, layout) of every live allocation
+    let mut live: AVLTree<usize, (usize, Layout)> = AVLTree::new();
+    let sample_every = (trace.events.len() / TIMELINE_SAMPLES).max(1);
+    let mut used_bytes = allocator.used_bytes();
+
+    for (operation, event) in trace.events.iter().enumerate() {
+        match *event {
+            TraceEvent::Alloc { id, size, align } => {
+                match Layout::from_size_align(size, align).map(|layout| (layout, allocator.allocate(layout))) {
+                    Ok((layout, Ok(address))) => {
+                        live.insert(id, (address, layout));
+                        used_bytes += allocator.rounded_size(size);
+                    }
+                    _ => report.failures += 1,
+                }
+            }
+            TraceEvent::Free { id } => {
+                if let Some((address, layout)) = live.remove(&id) {
+                    allocator.free_block(address)?;
+                    used_bytes -= allocator.rounded_size(layout.size());
+                }
+            }
+            TraceEvent::Realloc { id, size } => {
+                if let Some(&(address, layout)) = live.search(&id) {
+                    match allocator.reallocate(address, layout, size, |_, _, _| {}) {
+                        Ok(new_address) => {
+                            let new_layout = Layout::from_size_align(size, layout.align()).unwrap();
+                            live.insert(id, (new_address, new_layout));
+                            used_bytes = used_bytes - allocator.rounded_size(layout.size()) + allocator.rounded_size(size);
+                        }
+                        Err(_) => report.failures += 1,
+                    }
+                }
+            }
+        }
+
+        report.peak_used_bytes = report.peak_used_bytes.max(used_bytes);
+        report
+            .depths
+            .push(allocator.memory_tree.depth().max(allocator.free_addresses.depth()));
+        if (operation + 1) % sample_every == 0 || operation + 1 == trace.events.len() {
+            report.timeline.push(TimelineSample {
+                operation: operation + 1,
+                used_bytes,
+                fragmentation: allocator.stats().fragmentation,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+// `allocator replay <trace> [policy...]`: replays the trace once per policy, on a fresh
+// allocator over the trace's region each time, and prints a report for each
+#[cfg(not(target_os = "none"))]
+pub fn replay_main(path: &str, policies: &[String]) -> Result<(), String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let trace = Trace::parse(&text).map_err(|err| err.to_string())?;
+
+    let mut config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+    if let Some((start, size)) = trace.region {
+        config = config.region(start, size);
+    }
+    let default_policies = ["first-fit", "best-fit", "worst-fit", "next-fit"].map(String::from);
+    let policies = if policies.is_empty() { &default_policies[..] } else { policies };
+
+    for name in policies {
+        let policy = policy_by_name(name, 0).ok_or_else(|| format!("unknown policy {}", name))?;
+        let mut allocator = Allocator::with_config(config.clone())
+            .map_err(|err| err.to_string())?
+            .with_policy(policy);
+        let report = replay(&mut allocator, &trace).map_err(|err| err.to_string())?;
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+// `allocator record <trace> [operations]`: records a random mix of allocations, frees
+// and reallocs on a fresh allocator and writes it where replay can read it
+#[cfg(not(target_os = "none"))]
+pub fn record_main(path: &str, operations: usize) -> Result<(), String> {
+    let config = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+    let mut allocator = Allocator::with_config(config).map_err(|err| err.to_string())?;
+    allocator.start_trace();
+
+    let mut workload = Workload::new(operations as u64).mix(3, 3).max_align_exp(12);
+    let mut live: Vec<(usize, Layout)> = Vec::new();
+    for _ in 0..operations {
+        match workload.next(live.len()) {
+            Step::Free(index) => {
+                let (address, _) = live.swap_remove(index);
+                allocator.free_block(address).map_err(|err| err.to_string())?;
+            }
+            Step::Resize(index, size) => {
+                let (address, layout) = live[index];
+                if let Ok(moved) = allocator.reallocate(address, layout, size, |_, _, _| {}) {
+                    live[index] = (moved, Layout::from_size_align(size, layout.align()).unwrap());
+                }
+            }
+            Step::Allocate(layout) => {
+                if let Ok(address) = allocator.allocate(layout) {
+                    live.push((address, layout));
+                }
+            }
+        }
+    }
+
+    let trace = allocator.take_trace().unwrap_or_default();
+    std::fs::write(path, trace.to_string()).map_err(|err| format!("{}: {}", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_trace_replays_to_the_same_heap() {
+        let config = AllocatorConfig::default().seed(5);
+        let mut allocator = Allocator::with_config(config.clone()).unwrap();
+        let before = allocator.allocate(Layout::from_size_align(4096, 1).unwrap()).unwrap();
+        allocator.start_trace();
+
+        let first = allocator.allocate(Layout::from_size_align(100, 8).unwrap()).unwrap();
+        let second = allocator.allocate(Layout::from_size_align(50_000, 4096).unwrap()).unwrap();
+        allocator
+            .reallocate(first, Layout::from_size_align(100, 8).unwrap(), 70_000, |_, _, _| {})
+            .unwrap();
+        allocator.free_block(second).unwrap();
+        // Allocated before recording started, so not part of the trace
+        allocator.free_block(before).unwrap();
+        let trace = allocator.take_trace().unwrap();
+        assert_eq!(allocator.take_trace(), None);
+
+        assert_eq!(
+            trace.events,
+            [
+                TraceEvent::Alloc { id: 0, size: 100, align: 8 },
+                TraceEvent::Alloc { id: 1, size: 50_000, align: 4096 },
+                TraceEvent::Realloc { id: 0, size: 70_000 },
+                TraceEvent::Free { id: 1 },
+            ]
+        );
+        assert_eq!(Trace::parse(&trace.to_string()).unwrap(), trace);
+
+        let mut fresh = Allocator::with_config(config).unwrap();
+        let report = replay(&mut fresh, &trace).unwrap();
+        assert_eq!(report.failures, 0);
+        assert_eq!(fresh.used_bytes(), allocator.used_bytes());
+        assert_eq!(fresh.used_bytes(), allocator.rounded_size(70_000));
+    }
+}
@@ -0,0 +1,260 @@
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt;
+
+use crate::{AVLTree, AllocError, Allocator, AllocatorConfig};
+
+// Devices that can only address 24 or 32 bits need their buffers below these
+const DMA_LIMIT: usize = 16 << 20;
+const DMA32_LIMIT: usize = 4 << 30;
+// End of the kernel's direct map; memory above it is high memory
+const NORMAL_LIMIT: usize = 64 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    Dma,
+    Dma32,
+    Normal,
+    High,
+}
+
+impl Zone {
+    // Order allocations try zones in, so the scarce low zones are used last
+    const FALLBACK: [Zone; 4] = [Zone::High, Zone::Normal, Zone::Dma32, Zone::Dma];
+
+    fn of(address: usize) -> Zone {
+        match address {
+            _ if address < DMA_LIMIT => Zone::Dma,
+            _ if address < DMA32_LIMIT => Zone::Dma32,
+            _ if address < NORMAL_LIMIT => Zone::Normal,
+            _ => Zone::High,
+        }
+    }
+
+    // First address past the zone
+    fn limit(self) -> usize {
+        match self {
+            Zone::Dma => DMA_LIMIT,
+            Zone::Dma32 => DMA32_LIMIT,
+            Zone::Normal => NORMAL_LIMIT,
+            Zone::High => usize::MAX,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+            Zone::High => "High",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The zones an allocation may come from. Each named mask is a ceiling: DMA32 means
+// anywhere below 4 GiB, so the DMA zone is allowed too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneMask(u8);
+
+impl ZoneMask {
+    pub const DMA: ZoneMask = ZoneMask(0b0001);
+    pub const DMA32: ZoneMask = ZoneMask(0b0011);
+    pub const NORMAL: ZoneMask = ZoneMask(0b0111);
+    pub const HIGH: ZoneMask = ZoneMask(0b1111);
+
+    pub fn only(zone: Zone) -> ZoneMask {
+        ZoneMask(zone.bit())
+    }
+
+    pub fn contains(self, zone: Zone) -> bool {
+        self.0 & zone.bit() != 0
+    }
+}
+
+// One contiguous piece of memory inside a single zone, with an Allocator of its own
+#[derive(Debug)]
+struct ZoneRegion {
+    zone: Zone,
+    allocator: Allocator,
+}
+
+// Allocator per piece of usable memory, grouped by zone. The region list from boot is
+// cut at the zone limits, so every piece lies in exactly one zone.
+#[derive(Debug)]
+pub struct ZonedAllocator {
+    regions: Vec<ZoneRegion>,
+    // Start of every region to its index in regions, to find the owner of a block
+    by_start: AVLTree<usize, usize>,
+}
+
+impl ZonedAllocator {
+    // ranges are (start, length) of usable memory. template supplies everything but
+    // the region, and each piece is laid out as it says; ends are trimmed to the minimum
+    // block, and pieces too small to hold one are dropped.
+    pub fn from_regions(ranges: &[(usize, usize)], template: AllocatorConfig) -> Result<Self, AllocError> {
+        // Each piece's config is checked as its allocator is built, but the pieces have
+        // to be cut to the minimum block first
+        let min_block = 1usize
+            .checked_shl(template.min_block_exp)
+            .ok_or(AllocError::InvalidConfig("block exponents out of range"))?;
+        let mut zoned = ZonedAllocator {
+            regions: Vec::new(),
+            by_start: AVLTree::new(),
+        };
+
+        for &(start, len) in ranges {
+            let end = start.checked_add(len).ok_or(AllocError::InvalidConfig("region overflows"))? & !(min_block - 1);
+            let mut piece_start = start.checked_next_multiple_of(min_block).unwrap_or(end);
+
+            while piece_start < end {
+                let zone = Zone::of(piece_start);
+                let piece_end = end.min(zone.limit());
+                let config = template.clone().region(piece_start, piece_end - piece_start);
+                if zoned.by_start.floor(&(piece_end - 1)).is_some_and(|(_, &index)| {
+                    let region = &zoned.regions[index].allocator.config;
+                    region.region_start + region.region_size > piece_start
+                }) {
+                    return Err(AllocError::InvalidConfig("regions overlap"));
+                }
+
+                zoned.by_start.insert(piece_start, zoned.regions.len());
+                zoned.regions.push(ZoneRegion {
+                    zone,
+                    allocator: Allocator::with_config(config)?,
+                });
+                piece_start = piece_end;
+            }
+        }
+
+        if zoned.regions.is_empty() {
+            return Err(AllocError::InvalidConfig("no usable memory in the region list"));
+        }
+        Ok(zoned)
+    }
+
+    // Tries each zone the mask allows, highest first, and within a zone each of its
+    // regions in the order the region list gave them
+    pub fn allocate(&mut self, layout: Layout, mask: ZoneMask) -> Result<usize, AllocError> {
+        let mut result = Err(AllocError::OutOfMemory(layout));
+        for zone in Zone::FALLBACK.iter().filter(|&&zone| mask.contains(zone)) {
+            for region in self.regions.iter_mut().filter(|region| region.zone == *zone) {
+                result = region.allocator.allocate(layout);
+                match result {
+                    Ok(_) | Err(AllocError::InvalidLayout(_)) => return result,
+                    Err(_) => {}
+                }
+            }
+        }
+        result
+    }
+
+    pub fn free_block(&mut self, start: usize) -> Result<(), AllocError> {
+        self.region_of(start)
+            .ok_or(AllocError::UnknownAddress(start))?
+            .free_block(start)
+    }
+
+    // Allocator of the region holding address
+    fn region_of(&mut self, address: usize) -> Option<&mut Allocator> {
+        let (_, &index) = self.by_start.floor(&address)?;
+        let allocator = &mut self.regions[index].allocator;
+        let config = &allocator.config;
+        if address < config.region_start + config.region_size {
+            Some(allocator)
+        } else {
+            None
+        }
+    }
+
+    // (zone, total bytes, free bytes) for every zone that has any memory
+    pub fn zone_usage(&self) -> Vec<(Zone, usize, usize)> {
+        let mut usage: Vec<(Zone, usize, usize)> = Vec::new();
+        for region in &self.regions {
+            let total = region.allocator.config.region_size;
+            let free = region.allocator.free_bytes();
+            match usage.iter_mut().find(|(zone, _, _)| *zone == region.zone) {
+                Some(entry) => {
+                    entry.1 += total;
+                    entry.2 += free;
+                }
+                None => usage.push((region.zone, total, free)),
+            }
+        }
+        usage.sort_by_key(|(zone, _, _)| *zone);
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::RegionLayout;
+
+    const MIB: usize = 1 << 20;
+
+    #[test]
+    fn masks_pick_the_highest_allowed_zone_and_fall_back_down() {
+        // One range across the DMA limit, one across the end of the direct map
+        let ranges = [(MIB, 16 * MIB), (NORMAL_LIMIT - 4 * MIB, 8 * MIB)];
+        // Laid out in the largest blocks, so each megabyte is a block of its own
+        let template = AllocatorConfig::default().layout(RegionLayout::LargestAligned);
+        let mut zones = ZonedAllocator::from_regions(&ranges, template).unwrap();
+        let page = Layout::from_size_align(4096, 4096).unwrap();
+
+        let high = zones.allocate(page, ZoneMask::HIGH).unwrap();
+        let normal = zones.allocate(page, ZoneMask::NORMAL).unwrap();
+        let only_normal = zones.allocate(page, ZoneMask::only(Zone::Normal)).unwrap();
+        let dma = zones.allocate(page, ZoneMask::DMA).unwrap();
+        assert_eq!(Zone::of(high), Zone::High);
+        assert_eq!(Zone::of(normal), Zone::Normal);
+        assert_eq!(Zone::of(only_normal), Zone::Normal);
+        assert_eq!(Zone::of(dma), Zone::Dma);
+
+        // DMA32 holds a single megabyte, so the second one comes from DMA
+        let buffer = Layout::from_size_align(MIB, 4096).unwrap();
+        let first = zones.allocate(buffer, ZoneMask::DMA32).unwrap();
+        let second = zones.allocate(buffer, ZoneMask::DMA32).unwrap();
+        assert_eq!((Zone::of(first), Zone::of(second)), (Zone::Dma32, Zone::Dma));
+        assert!(zones.allocate(buffer, ZoneMask::only(Zone::Dma32)).is_err());
+
+        for start in [high, normal, only_normal, dma, first, second] {
+            zones.free_block(start).unwrap();
+        }
+        assert_eq!(zones.free_block(DMA32_LIMIT), Err(AllocError::UnknownAddress(DMA32_LIMIT)));
+        assert!(zones.zone_usage().iter().all(|&(_, total, free)| total == free));
+    }
+
+    #[test]
+    fn overlapping_regions_are_refused() {
+        let ranges = [(MIB, 4 * MIB), (2 * MIB, 4 * MIB)];
+        assert!(matches!(
+            ZonedAllocator::from_regions(&ranges, AllocatorConfig::default()),
+            Err(AllocError::InvalidConfig(_))
+        ));
+        let template = AllocatorConfig::default().block_exps(usize::BITS, usize::BITS + 1);
+        assert_eq!(
+            ZonedAllocator::from_regions(&[(MIB, MIB)], template).err(),
+            Some(AllocError::InvalidConfig("block exponents out of range"))
+        );
+    }
+
+    #[test]
+    fn pieces_are_laid_out_as_the_template_says() {
+        let largest_block = |layout| {
+            let template = AllocatorConfig::default().seed(5).layout(layout);
+            let zones = ZonedAllocator::from_regions(&[(MIB, 8 * MIB)], template).unwrap();
+            zones.regions[0].allocator.stats().largest_free_block
+        };
+        assert_eq!(largest_block(RegionLayout::LargestAligned), MIB);
+        // The Random layout never carves a block of the largest order
+        assert!(largest_block(RegionLayout::Random) < MIB);
+    }
+}